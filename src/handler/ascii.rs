@@ -0,0 +1,69 @@
+// TYPE A conversion between the NVT-ASCII on the wire (CRLF) and the native
+// line ending of the server (LF). The data comes in chunks, so a CR at the
+// end of a chunk is held until the next byte tells whether it's a CRLF.
+#[derive(Debug, Default)]
+pub struct AsciiTranslator {
+    pending_cr: bool,
+}
+
+impl AsciiTranslator {
+    pub fn new() -> Self {
+        AsciiTranslator::default()
+    }
+    // Wire to disk: CRLF -> LF, a lone CR is kept
+    pub fn translate_in(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len() + 1);
+        for &byte in input {
+            if self.pending_cr {
+                self.pending_cr = false;
+                if byte != b'\n' {
+                    out.push(b'\r');
+                }
+            }
+            if byte == b'\r' {
+                self.pending_cr = true;
+            } else {
+                out.push(byte);
+            }
+        }
+        out
+    }
+    // The end of the data, flush the CR held back by the last chunk
+    pub fn finish(&mut self) -> Vec<u8> {
+        if std::mem::take(&mut self.pending_cr) {
+            vec![b'\r']
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+// A NUL byte doesn't occur in a text file, it's the cheap check of a binary one
+pub fn looks_binary(block: &[u8]) -> bool {
+    block.contains(&0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate_in() {
+        let mut translator = AsciiTranslator::new();
+        assert_eq!(translator.translate_in(b"a\r\nb\r\n"), b"a\nb\n");
+        // CRLF split by the chunks
+        assert_eq!(translator.translate_in(b"c\r"), b"c");
+        assert_eq!(translator.translate_in(b"\nd"), b"\nd");
+        // A lone CR is data
+        assert_eq!(translator.translate_in(b"e\rf\r"), b"e\rf");
+        assert_eq!(translator.translate_in(b"\r\n"), b"\r\n");
+        assert_eq!(translator.translate_in(b"g\r"), b"g");
+        assert_eq!(translator.finish(), b"\r");
+        assert!(translator.finish().is_empty());
+    }
+    #[test]
+    fn test_looks_binary() {
+        assert!(!looks_binary(b"line\r\nline\n"));
+        assert!(looks_binary(b"\x7fELF\x02\x01\x01\x00"));
+    }
+}
@@ -0,0 +1,86 @@
+use crate::utils::config::{get_content, Config, Users};
+use log::debug;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+// Credential backend checked by USER and PASS
+pub trait Authenticator: Debug + Send + Sync {
+    // The password of the user, None for an unknown user
+    fn password(&self, user: &str) -> Option<String>;
+    // Refresh the profile of one user from the backend, false if it's gone
+    fn reload_user(&self, user: &str) -> Result<bool, String>;
+}
+
+// The users of the config file, cached until reloaded
+#[derive(Debug)]
+pub struct ConfigAuth {
+    path: Option<PathBuf>, // None when the users didn't come from a file
+    users: RwLock<Users>,
+}
+
+impl ConfigAuth {
+    pub fn new(users: Users, path: Option<PathBuf>) -> Self {
+        ConfigAuth {
+            path,
+            users: RwLock::new(users),
+        }
+    }
+}
+
+impl Authenticator for ConfigAuth {
+    fn password(&self, user: &str) -> Option<String> {
+        self.users.read().unwrap().get(user).cloned()
+    }
+    fn reload_user(&self, user: &str) -> Result<bool, String> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or("No credential file to reload from")?;
+        let content = get_content(path).ok_or(format!("Can't read {}", path.display()))?;
+        let config = serde_yaml::from_str::<Config>(&content).map_err(|e| e.to_string())?;
+        // The other users keep their cached profile
+        let mut users = self.users.write().unwrap();
+        debug!("Reload user {} from {}", user, path.display());
+        match config.users.get(user) {
+            Some(password) => {
+                users.insert(user.to_string(), password.clone());
+                Ok(true)
+            }
+            None => {
+                users.remove(user);
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_reload_user() {
+        let path = std::env::temp_dir().join(format!("miniftp_auth_{}.yaml", std::process::id()));
+        std::fs::write(&path, "users:\n  alice: old\n  bob: bob\n").unwrap();
+        let users = HashMap::from([
+            ("alice".to_string(), "old".to_string()),
+            ("bob".to_string(), "bob".to_string()),
+        ]);
+        let auth = ConfigAuth::new(users, Some(path.clone()));
+        std::fs::write(&path, "users:\n  alice: new\n  bob: changed\n").unwrap();
+        assert_eq!(auth.reload_user("alice"), Ok(true));
+        assert_eq!(auth.password("alice").as_deref(), Some("new"));
+        // Only the reloaded user is refreshed
+        assert_eq!(auth.password("bob").as_deref(), Some("bob"));
+        std::fs::write(&path, "users:\n  bob: bob\n").unwrap();
+        assert_eq!(auth.reload_user("alice"), Ok(false));
+        assert_eq!(auth.password("alice"), None);
+        std::fs::remove_file(&path).unwrap();
+        assert!(auth.reload_user("bob").is_err());
+        assert!(ConfigAuth::new(HashMap::new(), None)
+            .reload_user("bob")
+            .is_err());
+    }
+}
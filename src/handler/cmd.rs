@@ -1,30 +1,37 @@
 use super::error::{Error, Result};
-use enum_primitive_derive::Primitive;
+use super::reply_code::ReplyCode;
+use chrono::NaiveDateTime;
 use num_traits::FromPrimitive;
 use std::fmt;
-use std::path::PathBuf;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::result;
 use std::str::{self, FromStr};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Answer {
-    pub code: ResultCode,
+    pub code: ReplyCode,
     pub message: String,
 }
 
 impl Answer {
-    pub fn new(code: ResultCode, message: &str) -> Self {
+    pub fn new(code: ReplyCode, message: &str) -> Self {
         Answer {
             code,
             message: message.to_string(),
         }
     }
+    // Reply with the default text of the code
+    pub fn from_code(code: ReplyCode) -> Self {
+        Answer::new(code, code.default_message())
+    }
     pub fn from(buf: &str) -> Option<Self> {
         let s = buf.to_string();
         if let Some(index) = s.find(' ') {
             if index < 7 {
                 let (code, message) = s.split_at(index);
                 println!("code:{:?},msg:{:?}", code, message);
-                let code = ResultCode::from_i32(code.parse::<i32>().unwrap()).unwrap();
+                let code = ReplyCode::from_i32(code.parse::<i32>().unwrap()).unwrap();
                 return Some(Answer::new(code, &message[1..]));
             }
         }
@@ -49,32 +56,39 @@ pub enum Command {
     Cwd(PathBuf),
     User(String),
     Pass(String),
+    Auth(String),
     CdUp,
     Quit,
+    Rein,
     // Transfer parameter commands
-    Port(u16),
+    Port(SocketAddrV4),
     Type(TransferType),
     Pasv,
     // Query commands
     List(Option<PathBuf>),
     NLst(Option<PathBuf>),
-    Stat(PathBuf),
+    Mlsd(Option<PathBuf>),
+    Stat(Option<PathBuf>),
     Size(PathBuf),
+    // MDTM path queries the time, MDTM time path is the set form of some clients
+    Mdtm(Option<NaiveDateTime>, PathBuf),
     Help(String),
     Pwd,
     Syst,
     Acct,
     NoOp,
+    Opts(String),
     // File control commands
     Retr(PathBuf),
     Stor(PathBuf),
     Mkd(PathBuf),
     Rmd(PathBuf),
     Delete(PathBuf),
+    Mfmt(NaiveDateTime, PathBuf),
     Rnfr(PathBuf),
     Rnto(PathBuf),
     Site(Vec<String>),
-    Rest(String),
+    Rest(u64),
     Abort,
     Unknown(String),
 }
@@ -112,16 +126,20 @@ impl AsRef<str> for Command {
             Command::Acct => "ACCT",
             Command::Cwd(_) => "CWD",
             Command::Size(_) => "SIZE",
+            Command::Mdtm(..) => "MDTM",
+            Command::Mfmt(..) => "MFMT",
             Command::Pass(_) => "PASS",
             Command::List(_) => "LIST",
             Command::NLst(_) => "NLST",
+            Command::Mlsd(_) => "MLSD",
             Command::Mkd(_) => "MKD",
             Command::NoOp => "NOOP",
+            Command::Opts(_) => "OPTS",
             Command::Port(_) => "PORT",
             Command::Pasv => "PASV",
             Command::Pwd => "PWD",
             Command::Quit => "QUIT",
-            Command::Abort => "ABORT",
+            Command::Abort => "ABOR",
             Command::Rest(_) => "REST",
             Command::Site(_) => "SITE",
             Command::Retr(_) => "RETR",
@@ -136,80 +154,224 @@ impl AsRef<str> for Command {
             Command::Stat(_) => "STAT",
             Command::CdUp => "CDUP",
             Command::User(_) => "USER",
+            Command::Rein => "REIN",
+            Command::Auth(_) => "AUTH",
             Command::Unknown(_) => "UNKN",
         }
     }
 }
 
+// Why a command line is rejected, the argument errors are answered with 501
+// and the unsupported parameters with 504.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Empty,
+    MissingArgument(String),
+    InvalidArgument(String, String),
+    UnsupportedParameter(String, String),
+}
+
+impl ParseError {
+    pub fn reply_code(&self) -> ReplyCode {
+        match *self {
+            ParseError::UnsupportedParameter(..) => ReplyCode::ParamNotImplemented,
+            _ => ReplyCode::ParamSyntaxError,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Empty => write!(f, "empty command"),
+            ParseError::MissingArgument(ref verb) => write!(f, "{} needs a parameter", verb),
+            ParseError::InvalidArgument(ref verb, ref arg) => {
+                write!(f, "invalid {} parameter \"{}\"", verb, arg)
+            }
+            ParseError::UnsupportedParameter(ref verb, ref arg) => {
+                write!(f, "{} {} not implemented", verb, arg)
+            }
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Msg(e.to_string())
+    }
+}
+
 impl Command {
     pub fn new(input: Vec<u8>) -> Result<Self> {
-        let mut iter = input.split(|&byte| byte == b' ');
-        let command = iter
-            .next()
-            .ok_or_else(|| Error::Msg("empty command".to_string()))
-            .unwrap()
-            .to_vec();
-        // 先移除\r\
-        let command = String::from_utf8_lossy(&command).to_ascii_uppercase();
-        let data = iter
-            .next()
-            .ok_or_else(|| Error::Msg("no command parameter".to_string()));
-        let command = match command.as_bytes() {
-            b"Acct" => Command::Acct,
+        Ok(Command::parse(&input)?)
+    }
+    // Parse a line without its CRLF, the arguments are checked here once
+    pub fn parse(line: &[u8]) -> result::Result<Self, ParseError> {
+        let mut iter = line.split(|&byte| byte == b' ');
+        let verb = match iter.next() {
+            Some(verb) if !verb.is_empty() => String::from_utf8_lossy(verb).to_ascii_uppercase(),
+            _ => return Err(ParseError::Empty),
+        };
+        let data = iter.next();
+        let arg = || data.ok_or_else(|| ParseError::MissingArgument(verb.clone()));
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).to_string();
+        let path = || arg().map(|bytes| PathBuf::from(text(bytes)));
+        let command = match verb.as_bytes() {
+            b"ACCT" => Command::Acct,
             b"PASV" => Command::Pasv,
             b"PWD" => Command::Pwd,
             b"QUIT" => Command::Quit,
-            b"ABORT" => Command::Abort,
+            b"REIN" => Command::Rein,
+            b"ABOR" | b"ABORT" => Command::Abort,
             b"SYST" => Command::Syst,
             b"CDUP" => Command::CdUp,
             b"NOOP" => Command::NoOp,
-            b"REST" => Command::Rest(String::from_utf8_lossy(data?).to_string()),
-            b"CWD" => Command::Cwd(PathBuf::from(String::from_utf8_lossy(data?).to_string())),
-            b"SIZE" => Command::Size(PathBuf::from(String::from_utf8_lossy(data?).to_string())),
-            b"PASS" => Command::Pass(String::from_utf8_lossy(data?).to_string()),
-            b"RETR" => Command::Retr(PathBuf::from(String::from_utf8_lossy(data?).to_string())),
-            b"RNFR" => Command::Rnfr(PathBuf::from(String::from_utf8_lossy(data?).to_string())),
-            b"RNTO" => Command::Rnto(PathBuf::from(String::from_utf8_lossy(data?).to_string())),
-            b"STOR" => Command::Stor(PathBuf::from(String::from_utf8_lossy(data?).to_string())),
-            b"SITE" => Command::Site(
-                iter.map(|x| String::from_utf8_lossy(x).to_string())
-                    .collect(),
-            ),
-            b"STAT" => Command::Stat(PathBuf::from(String::from_utf8_lossy(data?).to_string())),
-            b"LIST" => Command::List(if data.is_ok() {
-                Some(PathBuf::from(String::from_utf8_lossy(data?).to_string()))
-            } else {
-                Some(PathBuf::from_str(".").unwrap())
-            }),
-            b"NLST" => Command::NLst(if data.is_ok() {
-                Some(PathBuf::from(String::from_utf8_lossy(data?).to_string()))
-            } else {
-                Some(PathBuf::from_str(".").unwrap())
-            }),
-            b"PORT" => extract_port(data?).unwrap(),
-            b"TYPE" => {
-                let error = Err("command not implemented for that parameter".into());
-                let data = data?;
-                if data.is_empty() {
-                    return error;
+            b"REST" => {
+                let offset = text(arg()?);
+                match offset.parse::<u64>() {
+                    Ok(n) => Command::Rest(n),
+                    Err(_) => return Err(ParseError::InvalidArgument(verb, offset)),
                 }
-                match TransferType::from(data[0]) {
-                    TransferType::Unknown => return error,
-                    typ => Command::Type(typ),
+            }
+            b"CWD" => Command::Cwd(path()?),
+            b"SIZE" => Command::Size(path()?),
+            b"MDTM" => {
+                let first = text(arg()?);
+                match (iter.next(), parse_time(&first)) {
+                    (Some(path), Some(time)) => {
+                        Command::Mdtm(Some(time), PathBuf::from(text(path)))
+                    }
+                    _ => Command::Mdtm(None, PathBuf::from(first)),
+                }
+            }
+            b"MFMT" => {
+                let time = text(arg()?);
+                let path = iter
+                    .next()
+                    .ok_or_else(|| ParseError::MissingArgument(verb.clone()))?;
+                match parse_time(&time) {
+                    Some(time) => Command::Mfmt(time, PathBuf::from(text(path))),
+                    None => return Err(ParseError::InvalidArgument(verb, time)),
                 }
             }
-            b"USER" => Command::User(String::from_utf8_lossy(data?).to_string()),
-            b"HELP" => Command::Help(String::from_utf8_lossy(data?).to_string()),
-            b"MKD" => Command::Mkd(PathBuf::from(String::from_utf8_lossy(data?).to_string())),
-            b"RMD" => Command::Rmd(PathBuf::from(String::from_utf8_lossy(data?).to_string())),
-            b"DELE" => Command::Delete(PathBuf::from(String::from_utf8_lossy(data?).to_string())),
-            s => Command::Unknown(str::from_utf8(s).unwrap_or("").to_owned()),
+            b"PASS" => Command::Pass(text(arg()?)),
+            b"AUTH" => Command::Auth(text(arg()?).to_ascii_uppercase()),
+            b"RETR" => Command::Retr(path()?),
+            b"RNFR" => Command::Rnfr(path()?),
+            b"RNTO" => Command::Rnto(path()?),
+            b"STOR" => Command::Stor(path()?),
+            b"SITE" => Command::Site(data.into_iter().chain(iter).map(text).collect()),
+            b"OPTS" => {
+                let name = arg().ok().filter(|name| !name.is_empty());
+                let name = name.ok_or_else(|| ParseError::MissingArgument(verb.clone()))?;
+                let option = std::iter::once(name)
+                    .chain(iter)
+                    .map(text)
+                    .collect::<Vec<_>>();
+                Command::Opts(option.join(" ").to_ascii_uppercase())
+            }
+            b"STAT" => Command::Stat(data.map(|data| PathBuf::from(text(data)))),
+            b"LIST" => Command::List(Some(path().unwrap_or_else(|_| PathBuf::from(".")))),
+            b"NLST" => Command::NLst(Some(path().unwrap_or_else(|_| PathBuf::from(".")))),
+            b"MLSD" => Command::Mlsd(Some(path().unwrap_or_else(|_| PathBuf::from(".")))),
+            b"PORT" => extract_port(arg()?)?,
+            b"TYPE" => {
+                let data = arg()?;
+                match data.first().map(|&c| TransferType::from(c)) {
+                    None => return Err(ParseError::MissingArgument(verb)),
+                    Some(TransferType::Unknown) => {
+                        return Err(ParseError::UnsupportedParameter(verb, text(data)))
+                    }
+                    Some(typ) => Command::Type(typ),
+                }
+            }
+            b"USER" => Command::User(text(arg()?)),
+            b"HELP" => Command::Help(text(arg()?)),
+            b"MKD" => Command::Mkd(path()?),
+            b"RMD" => Command::Rmd(path()?),
+            b"DELE" => Command::Delete(path()?),
+            _ => Command::Unknown(verb),
         };
         Ok(command)
     }
+    // The file or directory the command works on. CWD and the listings aren't here,
+    // they check their path themselves.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Command::Stat(Some(path))
+            | Command::Size(path)
+            | Command::Mdtm(_, path)
+            | Command::Retr(path)
+            | Command::Stor(path)
+            | Command::Mkd(path)
+            | Command::Rmd(path)
+            | Command::Delete(path)
+            | Command::Mfmt(_, path)
+            | Command::Rnfr(path)
+            | Command::Rnto(path) => Some(path),
+            _ => None,
+        }
+    }
 }
 
-pub fn extract_port(data: &[u8]) -> Result<Command> {
+// Verbs of RFC 959 and its extensions that the server doesn't implement,
+// they are answered with 502 rather than 500 for the unknown ones.
+const UNIMPLEMENTED_VERBS: [&str; 15] = [
+    "SMNT", "STOU", "APPE", "ALLO", "MODE", "STRU", "XCUP", "XCWD", "XMKD", "XPWD", "XRMD", "EPRT",
+    "EPSV", "LPRT", "LPSV",
+];
+
+pub fn is_unimplemented_verb(verb: &str) -> bool {
+    UNIMPLEMENTED_VERBS
+        .iter()
+        .any(|v| v.eq_ignore_ascii_case(verb))
+}
+
+const IAC: u8 = 255; // TELNET "interpret as command"
+const WILL: u8 = 251; // WILL, WONT, DO and DONT take an option byte
+
+// Drop the TELNET commands of a control line, e.g. IAC IP of the synch before ABOR.
+// IAC IAC is the data byte 255. A lone IAC is left when the DM was sent as urgent
+// data, out of the stream.
+pub fn strip_telnet(line: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(line.len());
+    let mut iter = line.iter().copied().peekable();
+    while let Some(byte) = iter.next() {
+        if byte != IAC {
+            out.push(byte);
+            continue;
+        }
+        match iter.peek().copied() {
+            Some(IAC) => {
+                out.push(IAC);
+                iter.next();
+            }
+            Some(command) if command >= WILL => {
+                iter.next();
+                iter.next();
+            }
+            Some(command) if command >= 240 => {
+                iter.next();
+            }
+            _ => (),
+        }
+    }
+    out
+}
+
+// The UTC time-val of RFC 3659, YYYYMMDDHHMMSS with optional .sss milliseconds
+pub fn parse_time(time: &str) -> Option<NaiveDateTime> {
+    let (secs, millis) = time.split_once('.').unwrap_or((time, ""));
+    let digits = |s: &str| s.bytes().all(|c| c.is_ascii_digit());
+    if secs.len() != 14 || !digits(secs) || millis.len() > 3 || !digits(millis) {
+        return None;
+    }
+    let time = NaiveDateTime::parse_from_str(secs, "%Y%m%d%H%M%S").ok()?;
+    let millis = format!("{:0<3}", millis).parse::<i64>().ok()?;
+    Some(time + chrono::Duration::milliseconds(millis))
+}
+
+pub fn extract_port(data: &[u8]) -> result::Result<Command, ParseError> {
     let addr = data
         .split(|&byte| byte == b',')
         .filter_map(|bytes| {
@@ -219,58 +381,101 @@ pub fn extract_port(data: &[u8]) -> Result<Command> {
         })
         .collect::<Vec<u8>>();
     if addr.len() != 6 {
-        return Err("Invalid address/port".into());
+        let data = String::from_utf8_lossy(data).to_string();
+        return Err(ParseError::InvalidArgument("PORT".to_string(), data));
     }
 
+    let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
     let port = (addr[4] as u16) << 8 | (addr[5] as u16);
-    if port <= 1024 {
-        return Err("Port can't be less than 1025".into());
-    }
-    Ok(Command::Port(port))
+    Ok(Command::Port(SocketAddrV4::new(ip, port)))
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Primitive)]
-pub enum ResultCode {
-    Series = 100,
-    RestartMakerReplay = 110,
-    ServiceReady = 120,
-    DataConnOpened = 125,
-    FileStatusOk = 150,
-    Ok = 200,
-    SysStatus = 211,
-    DirStatus = 212,
-    FileStatus = 213,
-    HelpMsg = 214,
-    NameSysType = 215,
-    ServiceReadyForUsr = 220,
-    ServiceCloseCtlCon = 221,
-    DataConnOpen = 225,
-    CloseDataClose = 226,
-    PassMode = 227,
-    LongPassMode = 228,
-    EntendedPassMode = 229,
-    Login = 230,
-    Logout = 231,
-    LogoutCmd = 232,
-    FileActOk = 250,
-    CreatPath = 257,
-    NeedPsw = 331,
-    NeedAccount = 332,
-    FileActionPending = 350,
-    ServiceNotAvail = 421,
-    DataConnFail = 425,
-    ConnClose = 426,
-    FileBusy = 450,
-    LocalErrr = 451,
-    NotEnoughSpace = 452,
-    SyntaxErr = 500,
-    CmdNotImpl = 502,
-    BadCmdSeq = 503,
-    CmdNotCmplParam = 504,
-    NotLogin = 530,
-    NeedAccountStoringFiles = 532,
-    FileNotFound = 550,
-    PageTypeUnknown = 551,
-    ExceededStorageAlloc = 552,
-    FileNameNotAllow = 553,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Command::parse(b"noop"), Ok(Command::NoOp));
+        assert_eq!(
+            Command::parse(b"RETR /tmp/a b"),
+            Ok(Command::Retr(PathBuf::from("/tmp/a")))
+        );
+        assert_eq!(Command::parse(b"REST 1024"), Ok(Command::Rest(1024)));
+        assert_eq!(
+            Command::parse(b"TYPE I"),
+            Ok(Command::Type(TransferType::BINARY))
+        );
+        assert_eq!(
+            Command::parse(b"PORT 10,0,0,1,117,48"),
+            Ok(Command::Port(SocketAddrV4::new(
+                Ipv4Addr::new(10, 0, 0, 1),
+                30000
+            )))
+        );
+        assert_eq!(
+            Command::parse(b"LIST"),
+            Ok(Command::List(Some(PathBuf::from("."))))
+        );
+        assert_eq!(Command::parse(b"STAT"), Ok(Command::Stat(None)));
+        assert_eq!(
+            Command::parse(b"SITE CHMOD 644 a"),
+            Ok(Command::Site(vec![
+                "CHMOD".into(),
+                "644".into(),
+                "a".into()
+            ]))
+        );
+        assert_eq!(
+            Command::parse(b"opts utf8 on"),
+            Ok(Command::Opts("UTF8 ON".to_string()))
+        );
+        assert_eq!(strip_telnet(b"\xff\xf4\xff\xf2ABOR"), b"ABOR");
+        assert_eq!(strip_telnet(b"\xff\xf4\xffABOR"), b"ABOR");
+        assert_eq!(strip_telnet(b"\xff\xfd\x01STAT \xff\xff"), b"STAT \xff");
+        let time = NaiveDateTime::parse_from_str("20200102030405", "%Y%m%d%H%M%S").unwrap();
+        assert_eq!(
+            Command::parse(b"MFMT 20200102030405 a"),
+            Ok(Command::Mfmt(time, PathBuf::from("a")))
+        );
+        assert_eq!(
+            Command::parse(b"MDTM 20200102030405 a"),
+            Ok(Command::Mdtm(Some(time), PathBuf::from("a")))
+        );
+        assert_eq!(
+            Command::parse(b"MDTM a"),
+            Ok(Command::Mdtm(None, PathBuf::from("a")))
+        );
+        assert_eq!(
+            parse_time("20200102030405.5"),
+            Some(time + chrono::Duration::milliseconds(500))
+        );
+        assert_eq!(
+            Command::parse(b"XYZ 1"),
+            Ok(Command::Unknown("XYZ".to_string()))
+        );
+    }
+    #[test]
+    fn test_parse_error() {
+        assert_eq!(Command::parse(b""), Err(ParseError::Empty));
+        let missing = Err(ParseError::MissingArgument("OPTS".to_string()));
+        assert_eq!(Command::parse(b"OPTS"), missing);
+        assert_eq!(Command::parse(b"OPTS "), missing);
+        let missing = Command::parse(b"RETR").unwrap_err();
+        assert_eq!(missing, ParseError::MissingArgument("RETR".to_string()));
+        assert_eq!(missing.reply_code(), ReplyCode::ParamSyntaxError);
+        let rest = Command::parse(b"REST -1").unwrap_err();
+        assert_eq!(
+            rest,
+            ParseError::InvalidArgument("REST".to_string(), "-1".to_string())
+        );
+        assert!(Command::parse(b"MFMT 2020 a").is_err());
+        assert!(Command::parse(b"MFMT 20200102030405").is_err());
+        assert_eq!(parse_time("20201302030405"), None);
+        assert!(Command::parse(b"PORT 10,0,0,1,117").is_err());
+        assert!(Command::parse(b"PORT 10,0,0,1,300,1").is_err());
+        let typ = Command::parse(b"TYPE X").unwrap_err();
+        assert_eq!(typ.reply_code(), ReplyCode::ParamNotImplemented);
+        assert_eq!(typ.to_string(), "TYPE X not implemented");
+    }
 }
@@ -37,10 +37,20 @@ impl Encoder for FtpCodec {
     type Error = io::Error;
     fn encode(&mut self, answer: Answer, buf: &mut Vec<u8>) -> io::Result<()> {
         let mut buffer = vec![];
+        let code = answer.code as u32;
         if answer.message.is_empty() {
-            write!(buffer, "{}\r\n", answer.code as u32)?;
+            write!(buffer, "{}\r\n", code)?;
+        } else if answer.message.contains("\r\n") {
+            // Multi-line reply: "xyz-first", the text lines, "xyz last"
+            let lines = answer.message.split("\r\n").collect::<Vec<&str>>();
+            write!(buffer, "{}-{}\r\n", code, lines[0])?;
+            for line in &lines[1..lines.len() - 1] {
+                // A text line starting with digits would be taken as the end
+                write!(buffer, " {}\r\n", line)?;
+            }
+            write!(buffer, "{} {}\r\n", code, lines[lines.len() - 1])?;
         } else {
-            write!(buffer, "{} {}\r\n", answer.code as u32, answer.message)?
+            write!(buffer, "{} {}\r\n", code, answer.message)?
         }
         buf.extend(&buffer);
         Ok(())
@@ -96,26 +106,26 @@ pub fn find_crlf(buf: &mut Vec<u8>) -> Option<usize> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::handler::cmd::ResultCode;
+    use crate::handler::reply_code::ReplyCode;
     use std::path::PathBuf;
     #[test]
     fn test_encoder() {
         let mut codec = FtpCodec;
         let message = "bad sequence of commands";
-        let answer = Answer::new(ResultCode::BadCmdSeq, message);
+        let answer = Answer::new(ReplyCode::BadSequence, message);
 
         let mut out = Vec::new();
         let result = "503 bad sequence of commands\r\n".as_bytes().to_vec();
 
         let code = answer.code;
         codec.encode(answer, &mut out).unwrap();
-        assert_eq!(code, ResultCode::BadCmdSeq);
+        assert_eq!(code, ReplyCode::BadSequence);
         assert_eq!(out, result);
     }
     #[test]
     fn test_encoder_msg() {
         let mut codec = FtpCodec;
-        let answer = Answer::new(ResultCode::CloseDataClose, "");
+        let answer = Answer::new(ReplyCode::ClosingDataConn, "");
         let mut out = Vec::new();
         codec.encode(answer, &mut out).unwrap();
 
@@ -123,11 +133,19 @@ mod tests {
         assert_eq!(out, result, r#"Buffer contain CloseDataClose"#);
     }
     #[test]
+    fn test_encoder_multi_line() {
+        let mut codec = FtpCodec;
+        let answer = Answer::new(ReplyCode::SystemStatus, "Status:\r\nTYPE: BINARY\r\nEnd");
+        let mut out = Vec::new();
+        codec.encode(answer, &mut out).unwrap();
+        assert_eq!(out, b"211-Status:\r\n TYPE: BINARY\r\n211 End\r\n".to_vec());
+    }
+    #[test]
     fn test_decoder() {
         let mut ftp_codec = FtpCodec;
         let mut client_codec = BytesCodec;
         let message = "bad sequence of commands";
-        let answer = Answer::new(ResultCode::BadCmdSeq, message);
+        let answer = Answer::new(ReplyCode::BadSequence, message);
 
         // Encode msg in server
         let mut msg = Vec::new();
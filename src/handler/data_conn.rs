@@ -0,0 +1,139 @@
+use crate::net::connection::Connection;
+use crate::net::event_loop::EventLoop;
+use crate::net::socket::Socket;
+use crate::utils::retry::retry_eintr;
+use log::{debug, warn};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{accept4, SockFlag};
+use nix::unistd::close;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::prelude::{IntoRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+// The data side of a session: the passive listener waiting for the client
+// and the data connection accepted from it. Both are released with the
+// manager, so an aborted session doesn't leak them.
+#[derive(Debug)]
+pub struct DataConnManager {
+    event_loop: EventLoop,
+    listener: Option<RawFd>,
+    conn: Option<Connection>,
+    held_at: Option<Instant>, // when the client connected before its transfer command
+    timeout: Duration,
+}
+
+impl DataConnManager {
+    pub fn new(event_loop: &EventLoop, timeout: Duration) -> Self {
+        DataConnManager {
+            event_loop: event_loop.clone(),
+            listener: None,
+            conn: None,
+            held_at: None,
+            timeout,
+        }
+    }
+    // Bind a new passive listener, the former one and its connection are closed
+    pub fn listen(&mut self, addr: &str) -> io::Result<u16> {
+        self.close();
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        self.listener = Some(listener.into_raw_fd());
+        debug!("Passive listener {:?} on port {}", self.listener, port);
+        Ok(port)
+    }
+    pub fn listen_fd(&self) -> Option<RawFd> {
+        self.listener
+    }
+    pub fn is_listening(&self) -> bool {
+        self.listener.is_some()
+    }
+    pub fn is_held(&self) -> bool {
+        self.conn.is_some()
+    }
+    // Accept without blocking a client which connected before its transfer command,
+    // the connection is held for the next transfer. It's dropped if no transfer
+    // command comes in time.
+    pub fn poll_accept(&mut self) {
+        if let Some(held_at) = self.held_at {
+            if held_at.elapsed() > self.timeout {
+                warn!(
+                    "No transfer command on the data connection for {:?}",
+                    self.timeout
+                );
+                self.close();
+            }
+            return;
+        }
+        if let Some(listener) = self.listener {
+            match retry_eintr(|| accept4(listener, SockFlag::SOCK_CLOEXEC)) {
+                Ok(fd) => {
+                    self.close_listener();
+                    self.conn = Some(Connection::new(Socket(fd)));
+                    self.held_at = Some(Instant::now());
+                    debug!("Hold passive data connection {}", fd);
+                }
+                Err(Errno::EAGAIN) => (),
+                Err(e) => warn!("Accept passive data connection failed: {}", e),
+            }
+        }
+    }
+    // The data connection of the next transfer, the client is waited for at most the timeout
+    pub fn accept(&mut self) -> Option<Connection> {
+        self.poll_accept();
+        if self.conn.is_none() && self.wait_client() {
+            self.poll_accept();
+        }
+        // One connection per PASV
+        self.close_listener();
+        self.held_at = None;
+        let mut conn = self.conn.take()?;
+        // It may be accepted by the worker of a former command
+        conn.attach_to_current_thread();
+        Some(conn)
+    }
+    fn wait_client(&self) -> bool {
+        let listener = match self.listener {
+            Some(fd) => fd,
+            None => return false,
+        };
+        let mut fds = [PollFd::new(listener, PollFlags::POLLIN)];
+        match poll(&mut fds, self.timeout.as_millis() as i32) {
+            Ok(0) => {
+                warn!("No client on the passive port for {:?}", self.timeout);
+                false
+            }
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Wait for the passive data connection failed: {}", e);
+                false
+            }
+        }
+    }
+    pub fn close(&mut self) {
+        self.close_listener();
+        self.held_at = None;
+        if let Some(mut conn) = self.conn.take() {
+            conn.shutdown();
+        }
+    }
+    // Deregister from the loop before closing, epoll mustn't watch a closed fd
+    fn close_listener(&mut self) {
+        if let Some(fd) = self.listener.take() {
+            if self.event_loop.is_registered(fd) {
+                self.event_loop.deregister(fd);
+            }
+            if let Err(e) = close(fd) {
+                warn!("Close passive listener {} occur {} error", fd, e);
+            }
+        }
+    }
+}
+
+impl Drop for DataConnManager {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
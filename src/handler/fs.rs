@@ -0,0 +1,48 @@
+use crate::utils::retry::retry_eintr;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, fsync, read, unlink, write};
+use std::fmt::Debug;
+use std::os::unix::prelude::RawFd;
+use std::path::Path;
+
+// Storage backend used by the file transfer commands
+pub trait FileSystem: Debug + Send + Sync {
+    fn open(&self, path: &Path, oflag: OFlag, mode: Mode) -> nix::Result<RawFd>;
+    fn write(&self, fd: RawFd, buf: &[u8]) -> nix::Result<usize>;
+    fn read(&self, fd: RawFd, buf: &mut [u8]) -> nix::Result<usize>;
+    fn fsync(&self, fd: RawFd) -> nix::Result<()>;
+    fn close(&self, fd: RawFd) -> nix::Result<()>;
+    fn unlink(&self, path: &Path) -> nix::Result<()>;
+    // Flush the directory entry, so that a new or renamed file survives a crash
+    fn fsync_dir(&self, dir: &Path) -> nix::Result<()> {
+        let fd = self.open(dir, OFlag::O_RDONLY | OFlag::O_DIRECTORY, Mode::empty())?;
+        let result = self.fsync(fd);
+        self.close(fd)?;
+        result
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalFs;
+
+impl FileSystem for LocalFs {
+    fn open(&self, path: &Path, oflag: OFlag, mode: Mode) -> nix::Result<RawFd> {
+        open(path, oflag, mode)
+    }
+    fn write(&self, fd: RawFd, buf: &[u8]) -> nix::Result<usize> {
+        retry_eintr(|| write(fd, buf))
+    }
+    fn read(&self, fd: RawFd, buf: &mut [u8]) -> nix::Result<usize> {
+        retry_eintr(|| read(fd, buf))
+    }
+    fn fsync(&self, fd: RawFd) -> nix::Result<()> {
+        fsync(fd)
+    }
+    fn close(&self, fd: RawFd) -> nix::Result<()> {
+        close(fd)
+    }
+    fn unlink(&self, path: &Path) -> nix::Result<()> {
+        unlink(path)
+    }
+}
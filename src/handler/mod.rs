@@ -6,8 +6,29 @@ pub mod session;
 #[allow(dead_code)]
 pub mod error;
 
+#[allow(dead_code)]
+pub mod reply_code;
+
 #[allow(dead_code)]
 pub mod codec;
 
 #[allow(dead_code)]
 pub mod speed_barrier;
+
+#[allow(dead_code)]
+pub mod fs;
+
+#[allow(dead_code)]
+pub mod ascii;
+
+#[allow(dead_code)]
+pub mod data_conn;
+
+#[allow(dead_code)]
+pub mod auth;
+
+#[allow(dead_code)]
+pub mod transfer;
+
+#[allow(dead_code)]
+pub mod token_bucket;
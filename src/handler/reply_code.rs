@@ -0,0 +1,142 @@
+use enum_primitive_derive::Primitive;
+use std::fmt;
+
+// Reply codes of RFC 959, with the extensions of RFC 2228 (security),
+// RFC 2428 (extended passive) and RFC 1639 (long passive)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Primitive)]
+pub enum ReplyCode {
+    RestartMarker = 110,
+    ServiceReadyInMinutes = 120,
+    DataConnAlreadyOpen = 125,
+    FileStatusOk = 150,
+    CommandOk = 200,
+    CommandSuperfluous = 202,
+    SystemStatus = 211,
+    DirectoryStatus = 212,
+    FileStatus = 213,
+    HelpMessage = 214,
+    SystemType = 215,
+    ServiceReady = 220,
+    ServiceClosing = 221,
+    DataConnOpen = 225,
+    ClosingDataConn = 226,
+    EnteringPassiveMode = 227,
+    EnteringLongPassiveMode = 228,
+    EnteringExtendedPassiveMode = 229,
+    LoggedIn = 230,
+    LoggedOut = 231,
+    LogoutNoted = 232,
+    SecurityDataExchangeOk = 234,
+    FileActionOk = 250,
+    PathCreated = 257,
+    NeedPassword = 331,
+    NeedAccount = 332,
+    FileActionPending = 350,
+    ServiceNotAvailable = 421,
+    SecurityResourceUnavailable = 431,
+    CantOpenDataConn = 425,
+    ConnClosed = 426,
+    FileBusy = 450,
+    LocalError = 451,
+    InsufficientStorage = 452,
+    SyntaxError = 500,
+    ParamSyntaxError = 501,
+    CommandNotImplemented = 502,
+    BadSequence = 503,
+    ParamNotImplemented = 504,
+    NotLoggedIn = 530,
+    NeedAccountForStoring = 532,
+    FileNotFound = 550,
+    PageTypeUnknown = 551,
+    ExceededStorage = 552,
+    FileNameNotAllowed = 553,
+}
+
+impl ReplyCode {
+    pub fn code(&self) -> u32 {
+        *self as u32
+    }
+    // The text suggested by the RFC, for the replies without more detail
+    pub fn default_message(&self) -> &'static str {
+        match *self {
+            ReplyCode::RestartMarker => "Restart marker reply",
+            ReplyCode::ServiceReadyInMinutes => "Service ready in a few minutes",
+            ReplyCode::DataConnAlreadyOpen => "Data connection already open; transfer starting",
+            ReplyCode::FileStatusOk => "File status okay; about to open data connection",
+            ReplyCode::CommandOk => "Command okay",
+            ReplyCode::CommandSuperfluous => "Command not implemented, superfluous at this site",
+            ReplyCode::SystemStatus => "System status",
+            ReplyCode::DirectoryStatus => "Directory status",
+            ReplyCode::FileStatus => "File status",
+            ReplyCode::HelpMessage => "Help message",
+            ReplyCode::SystemType => "UNIX Type: L8",
+            ReplyCode::ServiceReady => "Service ready for new user",
+            ReplyCode::ServiceClosing => "Service closing control connection",
+            ReplyCode::DataConnOpen => "Data connection open; no transfer in progress",
+            ReplyCode::ClosingDataConn => "Closing data connection",
+            ReplyCode::EnteringPassiveMode => "Entering Passive Mode",
+            ReplyCode::EnteringLongPassiveMode => "Entering Long Passive Mode",
+            ReplyCode::EnteringExtendedPassiveMode => "Entering Extended Passive Mode",
+            ReplyCode::LoggedIn => "User logged in, proceed",
+            ReplyCode::LoggedOut => "User logged out",
+            ReplyCode::LogoutNoted => "Logout command noted, will complete when transfer done",
+            ReplyCode::SecurityDataExchangeOk => "Security data exchange complete",
+            ReplyCode::FileActionOk => "Requested file action okay, completed",
+            ReplyCode::PathCreated => "Pathname created",
+            ReplyCode::NeedPassword => "User name okay, need password",
+            ReplyCode::NeedAccount => "Need account for login",
+            ReplyCode::FileActionPending => "Requested file action pending further information",
+            ReplyCode::ServiceNotAvailable => "Service not available, closing control connection",
+            ReplyCode::SecurityResourceUnavailable => {
+                "Need some unavailable resource to process security"
+            }
+            ReplyCode::CantOpenDataConn => "Can't open data connection",
+            ReplyCode::ConnClosed => "Connection closed; transfer aborted",
+            ReplyCode::FileBusy => "Requested file action not taken, file unavailable",
+            ReplyCode::LocalError => "Requested action aborted: local error in processing",
+            ReplyCode::InsufficientStorage => {
+                "Requested action not taken, insufficient storage space"
+            }
+            ReplyCode::SyntaxError => "Syntax error, command unrecognized",
+            ReplyCode::ParamSyntaxError => "Syntax error in parameters or arguments",
+            ReplyCode::CommandNotImplemented => "Command not implemented",
+            ReplyCode::BadSequence => "Bad sequence of commands",
+            ReplyCode::ParamNotImplemented => "Command not implemented for that parameter",
+            ReplyCode::NotLoggedIn => "Not logged in",
+            ReplyCode::NeedAccountForStoring => "Need account for storing files",
+            ReplyCode::FileNotFound => "Requested action not taken, file unavailable",
+            ReplyCode::PageTypeUnknown => "Requested action aborted: page type unknown",
+            ReplyCode::ExceededStorage => {
+                "Requested file action aborted, exceeded storage allocation"
+            }
+            ReplyCode::FileNameNotAllowed => "Requested action not taken, file name not allowed",
+        }
+    }
+}
+
+impl fmt::Display for ReplyCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_traits::FromPrimitive;
+
+    #[test]
+    fn test_reply_code() {
+        assert_eq!(ReplyCode::ServiceReady.code(), 220);
+        assert_eq!(ReplyCode::NotLoggedIn.code(), 530);
+        assert_eq!(ReplyCode::FileNotFound.code(), 550);
+        assert_eq!(ReplyCode::ConnClosed.to_string(), "426");
+        assert_eq!(ReplyCode::NotLoggedIn.default_message(), "Not logged in");
+        assert_eq!(
+            ReplyCode::BadSequence.default_message(),
+            "Bad sequence of commands"
+        );
+        assert_eq!(ReplyCode::from_u32(221), Some(ReplyCode::ServiceClosing));
+        assert_eq!(ReplyCode::from_u32(100), None);
+    }
+}
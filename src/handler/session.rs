@@ -1,29 +1,52 @@
-use crate::handler::codec::{Decoder, Encoder, FtpCodec};
+use crate::handler::ascii::{looks_binary, AsciiTranslator};
+use crate::handler::auth::{Authenticator, ConfigAuth};
+use crate::handler::codec::{Encoder, FtpCodec};
+use crate::handler::data_conn::DataConnManager;
+use crate::handler::fs::{FileSystem, LocalFs};
+use crate::handler::reply_code::ReplyCode;
 use crate::handler::speed_barrier::SpeedBarrier;
-use crate::net::acceptor::Acceptor;
-use crate::net::connection::Connection;
+use crate::handler::token_bucket::TokenBucket;
+use crate::handler::transfer::{Direction, TransferObserver, TransferOutcome, TransferResult};
+use crate::net::connection::{Connection, EventSet, TransferState, TransferTracker};
 use crate::net::event_loop::EventLoop;
+use crate::net::resolver::NameCache;
 use crate::net::socket::Socket;
+use crate::server::command_log::CommandLog;
+use crate::server::logins::{LoginCounter, LoginGuard};
+use crate::server::metrics::TransferMetrics;
 use crate::server::record_lock::FileLock;
-use crate::utils::config::Config;
+use crate::threadpool::threadpool::ThreadPool;
+use crate::utils::config::{BinaryGuard, Config};
+use crate::utils::retry::retry_eintr;
 use crate::utils::utils::is_regular;
 use crate::{handler::cmd::*, utils::utils::is_exist};
 use crate::{is_blk, is_char, is_dir, is_link, is_pipe, is_reg, is_sock};
 use chrono::prelude::*;
 use log::{debug, info, warn};
 use nix::dir::{Dir, Type};
-use nix::fcntl::{open, renameat, OFlag};
+use nix::errno::Errno;
+use nix::fcntl::{renameat, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::epoll::EpollFlags;
-use nix::sys::stat::{fchmodat, lstat, FchmodatFlags, Mode, SFlag};
-use nix::sys::utsname::uname;
-use nix::unistd::{close, ftruncate, lseek, mkdir, unlink, write};
+use nix::sys::stat::UtimensatFlags;
+use nix::sys::stat::{fchmodat, fstat, lstat, stat, utimensat, FchmodatFlags, Mode, SFlag};
+use nix::sys::time::TimeSpec;
+use nix::sys::uio::pread;
+use nix::unistd::{access, ftruncate, lseek, mkdir, pipe2, unlink, AccessFlags};
 use nix::unistd::{Gid, Group, Uid, User, Whence};
-use std::fs::canonicalize;
-use std::os::unix::prelude::AsRawFd;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::{canonicalize, File};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::prelude::{AsRawFd, FromRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Component, Path, PathBuf};
 use std::string::String;
-use std::time::Instant;
-use std::{collections::HashMap, net::TcpListener};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub const KILOGYTE: f64 = 1024f64;
 pub const MEGA_BYTE: f64 = KILOGYTE * 1024f64;
@@ -32,6 +55,11 @@ pub const GIGA_BYTE: f64 = MEGA_BYTE * 1024f64;
 const DEFAULT_DIR_PERM: u32 = 0x777;
 const DEAFULT_FILE_PERM: u32 = 0x666;
 const DEAFULT_SEND_SIZE: usize = 128 * 1024; // bytes
+const BINARY_CHECK_SIZE: usize = 4096; // bytes read for the binary guard of TYPE A
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1); // for the last replies before a close
+
+// Handle the parameters of an OPTS option
+type OptsHandler = fn(&mut Session, &str) -> Answer;
 
 #[derive(Debug, Clone)]
 enum DataType {
@@ -39,6 +67,37 @@ enum DataType {
     BINARY,
 }
 
+// What ended the wait for the next chunk of an upload
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DataWait {
+    Ready,
+    Aborted,  // by ABOR, or the control connection is gone
+    TimedOut, // nothing came for data_connect_timeout
+}
+
+// The lines of a directory listing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListStyle {
+    Names, // NLST
+    Long,  // LIST, in the ListFormat
+    Facts, // MLSD, the facts of RFC 3659
+}
+
+// Style of the LIST lines. The clients pick their LIST parser from the SYST
+// reply, so the system type comes from here.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListFormat {
+    Unix,
+}
+
+impl ListFormat {
+    pub fn system_type(&self) -> &'static str {
+        match *self {
+            ListFormat::Unix => "UNIX",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Context(pub(crate) i32, pub(crate) usize);
 
@@ -48,13 +107,14 @@ impl Context {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Session {
     cur_dir: PathBuf,
     file_name: Option<String>,
     cmd_conn: Connection,
-    data_conn: Option<Connection>,
+    data: DataConnManager,
     data_port: Option<u16>,
+    data_addr: Option<SocketAddr>, // the PORT address of active mode
     codec: FtpCodec,
     server_root: PathBuf,
     mode: u32, // for umask mode
@@ -68,17 +128,40 @@ pub struct Session {
     welcome: bool,
     resume_point: i64,
     help_map: HashMap<&'static str, &'static str>,
+    opts_map: HashMap<&'static str, OptsHandler>,
+    failed_commands: usize,         // error replies in a row
+    mutations: Option<TokenBucket>, // max_mutations_per_sec, made by the first mutation
+    command_log: Option<Arc<CommandLog>>,
+    names: Option<NameCache>,
+    fs: Arc<dyn FileSystem>,
+    auth: Arc<dyn Authenticator>,
+    logins: LoginCounter,
+    login: Option<LoginGuard>, // counted while logged in
+    transfer: TransferTracker, // state of the latest data connection
+    list_format: ListFormat,
+    metrics: Arc<TransferMetrics>,
+    observers: Vec<Arc<dyn TransferObserver>>,
+    listing_pool: Option<Arc<Mutex<ThreadPool>>>, // runs the stream_listing generators
 }
 
 impl Session {
-    pub fn new(config: &Config, conn: Connection, event_loop: &EventLoop) -> Self {
+    pub fn new(
+        config: &Config,
+        conn: Connection,
+        event_loop: &EventLoop,
+        names: Option<NameCache>,
+    ) -> Self {
         let root = User::from_uid(Uid::from_raw(0)).unwrap().unwrap();
         Session {
             cur_dir: canonicalize(root.dir.clone()).unwrap(),
             file_name: None,
             cmd_conn: conn,
-            data_conn: None,
+            data: DataConnManager::new(
+                event_loop,
+                Duration::from_secs(config.data_connect_timeout),
+            ),
             data_port: Some(22),
+            data_addr: None,
             codec: FtpCodec,
             server_root: canonicalize(root.dir.clone()).unwrap(),
             mode: 0x0,
@@ -92,9 +175,51 @@ impl Session {
             welcome: true,
             resume_point: 0,
             help_map: Self::get_help_map(),
+            opts_map: Self::get_opts_map(),
+            failed_commands: 0,
+            mutations: None,
+            command_log: None,
+            names,
+            fs: Arc::new(LocalFs),
+            auth: Arc::new(ConfigAuth::new(config.users.clone(), None)),
+            logins: LoginCounter::new(),
+            login: None,
+            transfer: TransferTracker::new(),
+            list_format: ListFormat::Unix,
+            metrics: TransferMetrics::new(),
+            observers: Vec::new(),
+            listing_pool: None,
         }
     }
+    // Report the transfers to the server wide metrics
+    pub fn set_metrics(&mut self, metrics: Arc<TransferMetrics>) {
+        self.metrics = metrics;
+    }
+    pub fn add_transfer_observer(&mut self, observer: Arc<dyn TransferObserver>) {
+        self.observers.push(observer);
+    }
+    // Check the credentials against a backend shared by the sessions
+    pub fn set_authenticator(&mut self, auth: Arc<dyn Authenticator>) {
+        self.auth = auth;
+    }
+    pub fn set_command_log(&mut self, log: Arc<CommandLog>) {
+        self.command_log = Some(log);
+    }
+    // Count the logins along with the other sessions of the server
+    pub fn set_login_counter(&mut self, logins: LoginCounter) {
+        self.logins = logins;
+    }
+    // Without it, stream_listing is off and a listing is made in memory
+    pub fn set_listing_pool(&mut self, pool: Arc<Mutex<ThreadPool>>) {
+        self.listing_pool = Some(pool);
+    }
+    // A different worker may run each command of the session, the event loop hands
+    // the session over to it before handle_command
+    pub fn attach_to_current_thread(&mut self) {
+        self.cmd_conn.attach_to_current_thread();
+    }
     pub fn handle_command(&mut self) {
+        self.data.poll_accept();
         // if revents.is_reable()
         if !self.cmd_conn.connected() {
             debug!("Session command is disconnnectd");
@@ -102,24 +227,114 @@ impl Session {
         }
         if self.welcome {
             self.welcome = false;
-            self.send_answer(Answer::new(
-                ResultCode::ServiceReadyForUsr,
-                "Welcome, tinyFTPd 3.0.3)",
-            ));
+            self.send_answer(Answer::new(ReplyCode::ServiceReady, "Welcome, tinyFTPd 3.0.3)"));
+        }
+        if self.cmd_conn.get_revents().is_urgent() {
+            self.synch();
+        }
+        // The rest of the replies left by a full socket
+        if self.cmd_conn.get_revents().is_writeable() && self.cmd_conn.has_pending_output() {
+            self.cmd_conn.flush_output();
+        }
+        let read_skipped = !self.accepts_input();
+        if read_skipped {
+            debug!("Backlog of {} commands, stop reading {}", self.backlog(), self.peer_name());
+        } else {
+            self.cmd_conn.fill_input();
+        }
+        for _ in 0..self.config.max_commands_per_read.max(1) {
+            match self.cmd_conn.next_line() {
+                Some(msg) => {
+                    self.process_line(msg);
+                    self.check_failed_commands();
+                }
+                None => break,
+            }
+            if !self.cmd_conn.connected() {
+                return;
+            }
+        }
+        // The rest is handled by the next wakeup, after the other sessions. A skipped
+        // read needs one too, its edge is gone while the socket may still hold commands.
+        if self.backlog() > 0 || read_skipped {
+            self.cmd_conn.rearm(&self.event_loop);
+        }
+    }
+    // "peer user command", the password isn't written
+    fn command_log_line(&self, line: &[u8]) -> String {
+        let user = self.name.as_deref().unwrap_or("-");
+        let verb = line.split(|&byte| byte == b' ').next().unwrap_or_default();
+        let command = if verb.eq_ignore_ascii_case(b"PASS") {
+            "PASS ****".into()
+        } else {
+            String::from_utf8_lossy(line)
+        };
+        format!("{} {} {}", self.peer_name(), user, command)
+    }
+    // Drop a client sending error after error, it's likely a scanner
+    fn check_failed_commands(&mut self) {
+        let max = self.config.max_failed_commands;
+        if max != 0 && self.failed_commands > max {
+            warn!(
+                "{} failed commands in a row from {}, close it",
+                self.failed_commands,
+                self.peer_name()
+            );
+            let message = "Too many failed commands, closing control connection";
+            self.send_answer(Answer::new(ReplyCode::ServiceNotAvailable, message));
+            // Not an abort: the reset would drop the 421 still in the send buffer
+            self.cmd_conn.drain_output(DRAIN_TIMEOUT);
+            self.cmd_conn.shutdown();
+        }
+    }
+    // Complete commands received but not handled yet
+    pub fn backlog(&self) -> usize {
+        self.cmd_conn.pending_lines()
+    }
+    // Back-pressure: a full backlog is handled before reading more
+    fn accepts_input(&self) -> bool {
+        self.backlog() < self.config.max_pending_commands.max(1)
+    }
+    fn process_line(&mut self, msg: Vec<u8>) {
+        if let Some(len) = argument_len(&msg) {
+            if len > self.config.max_arg_len {
+                let message = format!("Argument too long ({} > {})", len, self.config.max_arg_len);
+                self.send_answer(Answer::new(ReplyCode::ParamSyntaxError, &message));
+                return;
+            }
         }
-        let msg = self.cmd_conn.read_msg();
-        if msg.is_none() {
+        let line = strip_telnet(msg.strip_suffix(b"\r\n").unwrap_or(&msg));
+        let line = line.as_slice();
+        if let Some(log) = &self.command_log {
+            log.log(&self.command_log_line(line));
+        }
+        let cmd = match Command::parse(line) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                // The verb is known, but its argument is missing or malformed
+                debug!("Bad command {:?}: {}", String::from_utf8_lossy(line), e);
+                self.send_answer(Answer::new(e.reply_code(), &e.to_string()));
+                return;
+            }
+        };
+        if self.is_disabled(&cmd) {
+            let message = format!("{} is disabled", cmd.as_ref());
+            self.send_answer(Answer::new(ReplyCode::CommandNotImplemented, &message));
             return;
         }
-        let mut msg = msg.unwrap();
-        let cmd = self.codec.decode(&mut msg).unwrap().unwrap();
         info!(
             "A connection ({}->{}) command: {:?}",
-            self.cmd_conn.get_peer_addr(),
+            self.peer_name(),
             self.cmd_conn.get_local_addr(),
             cmd
         );
         if self.is_logged() {
+            if let Some(path) = cmd.path() {
+                if !self.path_allowed(&self.to_absolute(path.to_path_buf())) {
+                    self.send_answer(Answer::new(ReplyCode::FileNotFound, "Permission denied"));
+                    return;
+                }
+            }
             match cmd.clone() {
                 // Access control commands
                 Command::Cwd(dir) => self.cwd(self.to_absolute(dir)),
@@ -130,20 +345,36 @@ impl Session {
                 Command::Type(typ) => {
                     self.transfer_type = typ;
                     let message = format!("Opening {} mode to transfer files.", typ);
-                    self.send_answer(Answer::new(ResultCode::Ok, &message));
+                    self.send_answer(Answer::new(ReplyCode::CommandOk, &message));
                 }
                 // Query commands
-                Command::List(path) => self.list(path, true),
-                Command::NLst(path) => self.list(path, false),
+                Command::List(path) => self.list(path, ListStyle::Long),
+                Command::NLst(path) => self.list(path, ListStyle::Names),
+                Command::Mlsd(path) => self.list(path, ListStyle::Facts),
                 Command::Pwd => self.pwd(),
                 Command::Size(path) => self.size(self.to_absolute(path)),
+                Command::Mdtm(None, path) => self.mdtm(self.to_absolute(path)),
+                Command::Mdtm(Some(time), path) => {
+                    if self.config.mdtm_set {
+                        self.set_mtime(time, self.to_absolute(path));
+                    } else {
+                        let message = "MDTM with a time is disabled, use MFMT";
+                        self.send_answer(Answer::new(ReplyCode::ParamNotImplemented, message));
+                    }
+                }
                 Command::Help(content) => self.help(content),
+                Command::Stat(path) => self.stat(path),
                 // File control commands
-                Command::Stor(path) => self.stor(self.to_absolute(path)),
-                Command::Retr(path) => self.retr(self.to_absolute(path)),
+                Command::Stor(path) => {
+                    self.stor(self.to_absolute(path));
+                }
+                Command::Retr(path) => {
+                    self.retr(self.to_absolute(path));
+                }
                 Command::Mkd(path) => self.mkd(self.to_absolute(path)),
                 Command::Rmd(path) => self.rmd(self.to_absolute(path)),
                 Command::Delete(path) => self.delete(self.to_absolute(path)),
+                Command::Mfmt(time, path) => self.set_mtime(time, self.to_absolute(path)),
                 Command::Rnfr(path) => self.rnfr(self.to_absolute(path)),
                 Command::Rnto(path) => self.rnto(self.to_absolute(path)),
                 Command::Site(contents) => self.site(contents),
@@ -159,85 +390,85 @@ impl Session {
         match cmd {
             // Access control commands
             Command::User(content) => self.user(content),
+            Command::Auth(mechanism) => self.auth(mechanism),
             Command::Quit => self.quit(),
-            Command::Syst => {
-                let sys = uname();
-                let message = format!(
-                    "{} {} {} {} {}",
-                    sys.sysname(),
-                    sys.nodename(),
-                    sys.release(),
-                    sys.version(),
-                    sys.machine(),
-                );
-                self.send_answer(Answer::new(ResultCode::Ok, &message));
-            }
+            Command::Rein => self.rein(),
+            Command::Syst => self.syst(),
             Command::Acct => {
-                self.send_answer(Answer::new(ResultCode::CmdNotImpl, "Not implemented"))
+                self.send_answer(Answer::new(ReplyCode::CommandNotImplemented, "Not implemented"))
+            }
+            Command::NoOp => self.send_answer(Answer::new(ReplyCode::CommandOk, "Doing nothing")),
+            Command::Opts(option) => self.opts(option),
+            Command::Unknown(s) if is_unimplemented_verb(&s) => {
+                let message = format!("\"{}\": not implemented", s);
+                self.send_answer(Answer::new(ReplyCode::CommandNotImplemented, &message));
             }
-            Command::NoOp => self.send_answer(Answer::new(ResultCode::Ok, "Doing nothing")),
             Command::Unknown(s) => {
-                self.send_answer(Answer::new(
-                    ResultCode::SyntaxErr,
-                    &format!("\"{}\": not implemented", s),
-                ));
+                let message = format!("\"{}\": command unrecognized", s);
+                self.send_answer(Answer::new(ReplyCode::SyntaxError, &message));
             }
             _ => (),
         }
     }
     // TODO: check passwd, and cd to current user directory
     fn pass(&mut self, content: String) {
-        let ok = if self.is_admin {
-            content.eq(&self.config.users[&self.name.clone().unwrap()])
-        } else {
-            content.eq(&self.config.users[&(self.name.clone().unwrap())])
-        };
+        let ok = self.auth.password(self.name.as_ref().unwrap()) == Some(content);
         if ok {
             self.waiting_password = false;
-            self.send_answer(Answer::new(
-                ResultCode::Login,
-                &format!("Welcome {}", self.name.clone().unwrap()),
-            ));
+            self.logged_in(&format!("Welcome {}", self.name.clone().unwrap()));
         } else {
-            self.send_answer(Answer::new(ResultCode::LongPassMode, "Invalid password...."));
+            self.send_answer(Answer::new(ReplyCode::NotLoggedIn, "Invalid password...."));
         }
     }
     // TODO: check passwd, and cd to current user directory
+    fn auth(&mut self, mechanism: String) {
+        match mechanism.as_str() {
+            "TLS" | "TLS-C" | "SSL" => {
+                // There isn't a TLS implementation to negotiate with yet
+                let message = "TLS is not available on this server";
+                self.send_answer(Answer::new(ReplyCode::SecurityResourceUnavailable, message));
+            }
+            _ => {
+                let message = format!("AUTH {} is unsupported", mechanism);
+                self.send_answer(Answer::new(ReplyCode::ParamNotImplemented, &message));
+            }
+        }
+    }
     fn user(&mut self, content: String) {
         if content.is_empty() {
-            self.send_answer(Answer::new(ResultCode::CmdNotCmplParam, "Invaild username"));
+            self.send_answer(Answer::new(ReplyCode::ParamNotImplemented, "Invaild username"));
         } else {
             let mut name: Option<String> = None;
             let mut pass_required = true;
             self.is_admin = false;
+            self.login = None; // log out the former user
 
             if let Some(ref admin) = self.config.admin {
                 if content.eq(admin) {
                     self.is_admin = true;
                     name = Some(content.clone());
-                    pass_required = !self.config.users[&(content.clone())].is_empty();
+                    pass_required = !self.auth.password(&content).is_some_and(|p| p.is_empty());
                 }
             }
             if name.is_none() {
-                if self.config.users.contains_key(&content) {
+                if let Some(password) = self.auth.password(&content) {
                     name = Some(content.clone());
-                    pass_required = !self.config.users[&(content.clone())].is_empty()
+                    pass_required = !password.is_empty()
                 }
             }
             if name.is_none() {
-                self.send_answer(Answer::new(ResultCode::NotLogin, "Unknown user..."));
+                self.send_answer(Answer::new(ReplyCode::NotLoggedIn, "Unknown user..."));
             } else {
                 self.name = name.clone();
                 if pass_required {
                     self.waiting_password = true;
                     self.send_answer(Answer::new(
-                        ResultCode::NeedPsw,
+                        ReplyCode::NeedPassword,
                         &format!("Login Ok, password needed for {}", name.clone().unwrap()),
                     ));
                 } else {
                     self.waiting_password = false;
-                    let message = format!("Login successful.");
-                    self.send_answer(Answer::new(ResultCode::Login, &message));
+                    self.logged_in("Login successful.");
                 }
             }
             let user_dir = Path::new("/home").join(name.clone().unwrap_or(String::new()));
@@ -253,25 +484,120 @@ impl Session {
             self.cur_dir
         );
     }
+    // Close the session if the user already has its number of sessions
+    fn logged_in(&mut self, message: &str) {
+        let name = self.name.clone().unwrap();
+        self.login = self.logins.acquire(&name, self.config.max_logins_per_user);
+        if self.login.is_some() {
+            self.send_answer(Answer::new(ReplyCode::LoggedIn, message));
+        } else {
+            warn!(
+                "{} has {} sessions, refuse {}",
+                name,
+                self.logins.count(&name),
+                self.peer_name()
+            );
+            self.name = None;
+            let message = "Too many connections for this account";
+            self.send_answer(Answer::new(ReplyCode::NotLoggedIn, message));
+            self.cmd_conn.drain_output(DRAIN_TIMEOUT);
+            self.cmd_conn.shutdown();
+        }
+    }
     pub fn get_data_conn(&mut self) -> Option<Connection> {
         let port = if let Some(port) = self.data_port { port } else { 22 };
-        if self.pasv_enable {
-            let c = self.data_conn.to_owned();
-            self.data_conn = None;
-            return c;
+        let mut conn = if self.pasv_enable {
+            let conn = self.data.accept()?;
+            let mut sock = conn.get_fd();
+            sock.set_keep_alive(true);
+            sock.set_no_delay(true);
+            sock.mark_tos(self.config.data_tos);
+            sock.mark_user_timeout(self.config.data_user_timeout);
+            conn
         } else {
-            let addr = format!("127.0.0.1:{}", port);
-            let mut sock = Socket::connect(&addr);
+            let addr = self.data_addr.unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], port)));
+            let mut sock = Socket::connect(&addr.to_string());
             sock.set_keep_alive(true);
             sock.set_no_delay(true);
             sock.set_reuse_addr(true);
             sock.set_reuse_port(true);
-            return Some(Connection::new(sock));
+            sock.mark_tos(self.config.data_tos);
+            sock.mark_user_timeout(self.config.data_user_timeout);
+            Connection::new(sock)
+        };
+        conn.track_transfer(self.transfer.clone());
+        conn.set_id(format!("{}.data", self.cmd_conn.id()));
+        debug!("[{}] Data connection {}", conn.id(), conn.get_peer_addr());
+        Some(conn)
+    }
+    pub fn shutdown(&mut self) {
+        self.data.close();
+        if self.cmd_conn.connected() {
+            self.cmd_conn.shutdown();
         }
     }
+    // Shared with the server, which reads it while the session is locked by a transfer
+    pub fn transfer_tracker(&self) -> TransferTracker {
+        self.transfer.clone()
+    }
+    pub fn transfer_state(&self) -> TransferState {
+        self.transfer.get()
+    }
     pub fn set_revents(&mut self, revents: &EpollFlags) {
         self.cmd_conn.set_revents(revents);
     }
+    // Peer address for logging, the host name replaces the ip once it's resolved
+    pub fn peer_name(&self) -> String {
+        let peer_addr = self.cmd_conn.get_peer_addr();
+        match (&self.names, self.cmd_conn.peer_ip()) {
+            (Some(names), Some(ip)) => {
+                let port = peer_addr.rsplit(':').next().unwrap_or("");
+                format!("{}:{}", names.lookup(&ip), port)
+            }
+            _ => peer_addr,
+        }
+    }
+    // The canonical path, if it's allowed. The symlinks are resolved before the jail
+    // check, so a link to a directory of the root is fine while a link out of it isn't.
+    fn resolve_in_jail(&self, path: &Path) -> Option<PathBuf> {
+        if !self.config.follow_symlinks {
+            // Out of the root, e.g. without the jail, every component is checked
+            let (relative, base) = match path.strip_prefix(&self.server_root) {
+                Ok(relative) => (relative, self.server_root.as_path()),
+                Err(_) => (path, Path::new("/")),
+            };
+            if has_symlink(relative, base) {
+                return None;
+            }
+        }
+        let canonical = canonicalize(path).ok()?;
+        if self.config.jail && !canonical.starts_with(&self.server_root) {
+            debug!("{:?} is out of the root {:?}", canonical, self.server_root);
+            return None;
+        }
+        Some(canonical)
+    }
+    // Whether a path command may work on the path, under the same rules as CWD and
+    // LIST. A new file or directory, e.g. of STOR or MKD, is checked by its parent.
+    fn path_allowed(&self, path: &Path) -> bool {
+        if self.config.follow_symlinks && !self.config.jail {
+            return true;
+        }
+        if self.resolve_in_jail(path).is_some() {
+            return true;
+        }
+        if path.symlink_metadata().is_ok() {
+            return false;
+        }
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(_)) => self.resolve_in_jail(parent).is_some(),
+            _ => false,
+        }
+    }
+    fn is_disabled(&self, cmd: &Command) -> bool {
+        let verb = cmd.as_ref();
+        self.config.disabled_commands.iter().any(|c| c.eq_ignore_ascii_case(verb))
+    }
     fn is_logged(&self) -> bool {
         self.name.is_some() && !self.waiting_password
     }
@@ -281,7 +607,21 @@ impl Session {
         }
         path
     }
+    // A burst of DELE, RMD, RNTO or MKD beyond max_mutations_per_sec is refused with 450,
+    // the client may retry it later
+    fn allow_mutation(&mut self) -> bool {
+        let rate = self.config.max_mutations_per_sec;
+        if rate == 0 || self.mutations.get_or_insert_with(|| TokenBucket::new(rate)).try_take() {
+            return true;
+        }
+        debug!("Too many operations from {}", self.peer_name());
+        self.send_answer(Answer::new(ReplyCode::FileBusy, "Too many operations"));
+        false
+    }
     fn mkd(&mut self, path: PathBuf) {
+        if !self.allow_mutation() {
+            return;
+        }
         let mut ok = false;
         let path = path.to_str().unwrap();
         if self.is_admin && !is_exist(path) {
@@ -295,24 +635,30 @@ impl Session {
         }
         if ok {
             let message = &format!("Folder {} successfully created!", path);
-            self.send_answer(Answer::new(ResultCode::FileActOk, &message));
+            self.send_answer(Answer::new(ReplyCode::FileActionOk, &message));
         } else {
-            self.send_answer(Answer::new(ResultCode::FileNameNotAllow, "Couldn't create folder"));
+            self.send_answer(Answer::new(ReplyCode::FileNameNotAllowed, "Couldn't create folder"));
         }
     }
     fn rmd(&mut self, path: PathBuf) {
+        if !self.allow_mutation() {
+            return;
+        }
         // check path
         if self.is_admin
             && is_exist(path.to_str().unwrap_or(""))
             && path.is_dir()
             && remove_dir_all(&path)
         {
-            self.send_answer(Answer::new(ResultCode::FileActOk, "Folder successufully removed"));
+            self.send_answer(Answer::new(ReplyCode::FileActionOk, "Folder successufully removed"));
         } else {
-            self.send_answer(Answer::new(ResultCode::FileNotFound, "Couldn't remove folder"));
+            self.send_answer(Answer::new(ReplyCode::FileNotFound, "Couldn't remove folder"));
         }
     }
     fn delete(&mut self, path: PathBuf) {
+        if !self.allow_mutation() {
+            return;
+        }
         let mut ok = false;
         let path = if path.is_absolute() { path } else { self.cur_dir.join(path) };
         if is_exist(path.to_str().unwrap()) && is_regular(path.to_str().unwrap()) {
@@ -324,12 +670,12 @@ impl Session {
         let name = path.file_name().unwrap().to_str().unwrap();
         if ok {
             self.send_answer(Answer::new(
-                ResultCode::FileActOk,
+                ReplyCode::FileActionOk,
                 &format!("File {} successufully removed", name),
             ));
         } else {
             self.send_answer(Answer::new(
-                ResultCode::FileNotFound,
+                ReplyCode::FileNotFound,
                 &format!("Couldn't remove file {}", name),
             ));
         }
@@ -339,17 +685,20 @@ impl Session {
         if self.is_admin && is_exist(file_name) && is_regular(file_name) {
             self.file_name = Some(file_name.to_string());
             self.send_answer(Answer::new(
-                ResultCode::FileActionPending,
+                ReplyCode::FileActionPending,
                 &format!("Ready for rename file {}", file_name),
             ));
         } else {
             self.send_answer(Answer::new(
-                ResultCode::FileNotFound,
+                ReplyCode::FileNotFound,
                 &format!("Couldn't rename file {}", file_name),
             ));
         }
     }
     fn rnto(&mut self, path: PathBuf) {
+        if !self.allow_mutation() {
+            return;
+        }
         let mut ok = false;
         let new_file = path.as_path().to_string_lossy().to_string();
         let old_path = self.file_name.clone();
@@ -362,49 +711,143 @@ impl Session {
         if ok {
             let message =
                 format!("Rename file {} successful rename to {}", old_path.unwrap(), new_file);
-            self.send_answer(Answer::new(ResultCode::FileActOk, &message));
+            self.send_answer(Answer::new(ReplyCode::FileActionOk, &message));
         } else {
-            self.send_answer(Answer::new(ResultCode::FileNameNotAllow, "Coldn't rename file"));
+            self.send_answer(Answer::new(ReplyCode::FileNameNotAllowed, "Coldn't rename file"));
         }
     }
     fn site(&mut self, contents: Vec<String>) {
         debug!("Site: {:?}", contents);
-        let mut ok = false;
-        if contents.len() == 2 && contents[0] == "umask" {
-            if let Ok(mode) = contents[1].parse::<u32>() {
-                self.mode = mode;
-                ok = true;
-                self.send_answer(Answer::new(ResultCode::Ok, &format!("UMASK set to {}", mode)));
+        let name = contents.first().map(|name| name.to_ascii_uppercase()).unwrap_or_default();
+        let args = contents.get(1..).unwrap_or_default();
+        match (name.as_str(), args) {
+            ("UMASK", [mode]) => {
+                if let Ok(mode) = mode.parse::<u32>() {
+                    self.mode = mode;
+                    let message = format!("UMASK set to {}", mode);
+                    self.send_answer(Answer::new(ReplyCode::CommandOk, &message));
+                } else {
+                    let message = format!("Invalid mode {}", mode);
+                    self.send_answer(Answer::new(ReplyCode::ParamSyntaxError, &message));
+                }
+            }
+            ("SELFTEST", []) => {
+                if !self.is_admin {
+                    self.send_answer(Answer::new(ReplyCode::FileNotFound, "Permission denied"));
+                } else if let Err(reason) = self.storage_selftest() {
+                    warn!("Storage self test failed: {}", reason);
+                    let message = format!("Storage failure: {}", reason);
+                    self.send_answer(Answer::new(ReplyCode::FileBusy, &message));
+                } else {
+                    self.send_answer(Answer::new(ReplyCode::CommandOk, "Storage OK"));
+                }
             }
-        } else if contents.len() == 3 && contents[0] == "chmod" {
-            if let Ok(mode) = contents[1].parse::<u32>() {
-                ok = fchmodat(
+            ("RELOADUSER", [user]) => self.reload_user(user),
+            ("CHMOD", [mode, path]) => {
+                let mode = match mode.parse::<u32>() {
+                    Ok(mode) => mode,
+                    Err(_) => {
+                        let message = format!("Invalid mode {}", mode);
+                        self.send_answer(Answer::new(ReplyCode::ParamSyntaxError, &message));
+                        return;
+                    }
+                };
+                let result = fchmodat(
                     None,
-                    Path::new(&contents[2]),
+                    Path::new(path),
                     Mode::from_bits(mode).unwrap_or(Mode::all()),
                     FchmodatFlags::NoFollowSymlink,
-                )
-                .is_ok();
-                if ok {
-                    let message = format!("chmod {} {}", mode, contents[2]);
-                    self.send_answer(Answer::new(ResultCode::Ok, &message));
+                );
+                if let Err(e) = result {
+                    let message = format!("chmod {} failed: {}", path, e);
+                    self.send_answer(Answer::new(ReplyCode::FileNotFound, &message));
+                } else {
+                    let message = format!("chmod {} {}", mode, path);
+                    self.send_answer(Answer::new(ReplyCode::CommandOk, &message));
                 }
             }
+            ("UMASK" | "SELFTEST" | "RELOADUSER" | "CHMOD", _) => {
+                let message = format!("Wrong number of arguments for SITE {}", name);
+                self.send_answer(Answer::new(ReplyCode::ParamSyntaxError, &message));
+            }
+            _ => {
+                let message = format!("Unknown site command: {:?}.", contents);
+                self.send_answer(Answer::new(ReplyCode::CommandNotImplemented, &message));
+            }
+        }
+    }
+
+    // Refresh one user from the credential backend, the others are left untouched
+    fn reload_user(&mut self, user: &str) {
+        if !self.is_admin {
+            self.send_answer(Answer::new(ReplyCode::FileNotFound, "Permission denied"));
+            return;
         }
-        if !ok {
-            let message = format!("Unknown site command: {:?}.", contents);
-            self.send_answer(Answer::new(ResultCode::BadCmdSeq, &message));
+        match self.auth.reload_user(user) {
+            Ok(true) => {
+                info!("User {} reloaded", user);
+                let message = format!("User {} reloaded", user);
+                self.send_answer(Answer::new(ReplyCode::CommandOk, &message));
+            }
+            Ok(false) => {
+                info!("User {} removed", user);
+                let message = format!("User {} removed", user);
+                self.send_answer(Answer::new(ReplyCode::CommandOk, &message));
+            }
+            Err(reason) => {
+                warn!("Reload user {} failed: {}", user, reason);
+                let message = format!("Can't reload user {}: {}", user, reason);
+                self.send_answer(Answer::new(ReplyCode::FileBusy, &message));
+            }
         }
     }
 
-    fn rest(&mut self, content: String) {
-        if let Ok(n) = content.parse::<i64>() {
+    // Create, write, read back and delete a small file in the root, through the storage backend
+    fn storage_selftest(&self) -> Result<(), String> {
+        const PAYLOAD: &[u8] = b"miniftp storage self test";
+        let name = format!(".miniftp_selftest_{}", std::process::id());
+        let path = self.server_root.join(name);
+        let path = path.as_path();
+        let step = |step: &str, e: Errno| format!("{} {}: {}", step, path.display(), e);
+
+        let oflag = OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_WRONLY;
+        let fd = self
+            .fs
+            .open(path, oflag, Mode::S_IRUSR | Mode::S_IWUSR)
+            .map_err(|e| step("create", e))?;
+        let written = self.fs.write(fd, PAYLOAD);
+        let closed = self.fs.close(fd);
+        let result = match (written, closed) {
+            (Ok(n), Ok(())) if n == PAYLOAD.len() => Ok(()),
+            (Ok(n), Ok(())) => {
+                Err(format!("write {}: {} of {} bytes", path.display(), n, PAYLOAD.len()))
+            }
+            (Err(e), _) => Err(step("write", e)),
+            (_, Err(e)) => Err(step("close", e)),
+        }
+        .and_then(|_| {
+            let fd =
+                self.fs.open(path, OFlag::O_RDONLY, Mode::empty()).map_err(|e| step("open", e))?;
+            let mut buf = [0u8; 64];
+            let read = self.fs.read(fd, &mut buf);
+            self.fs.close(fd).unwrap_or_default();
+            match read {
+                Ok(n) if &buf[..n] == PAYLOAD => Ok(()),
+                Ok(_) => Err(format!("read {}: content mismatch", path.display())),
+                Err(e) => Err(step("read", e)),
+            }
+        });
+        let removed = self.fs.unlink(path).map_err(|e| step("delete", e));
+        result.and(removed)
+    }
+    fn rest(&mut self, offset: u64) {
+        if let Ok(n) = i64::try_from(offset) {
             self.resume_point = n;
             let message =
                 format!("Restarting at {}. execute get, put or append to initiate transfer", n);
-            self.send_answer(Answer::new(ResultCode::FileActionPending, &message));
+            self.send_answer(Answer::new(ReplyCode::FileActionPending, &message));
         } else {
-            self.send_answer(Answer::new(ResultCode::BadCmdSeq, "Couldn't restart break point"));
+            self.send_answer(Answer::new(ReplyCode::BadSequence, "Couldn't restart break point"));
         }
     }
     fn cwd(&mut self, dir: PathBuf) {
@@ -412,7 +855,7 @@ impl Session {
         let mut ok = false;
         // check path invalid and exist
         if is_exist(directory) && dir.is_dir() {
-            if let Ok(dir) = canonicalize(dir.clone()) {
+            if let Some(dir) = self.resolve_in_jail(&dir) {
                 let current_dir = dir;
                 self.cur_dir = current_dir;
                 ok = true;
@@ -420,99 +863,288 @@ impl Session {
         }
         if ok {
             self.send_answer(Answer::new(
-                ResultCode::FileActOk,
+                ReplyCode::FileActionOk,
                 "Change current path successfully",
             ));
         } else {
-            self.send_answer(Answer::new(ResultCode::FileNotFound, "No such file or directory"));
+            self.send_answer(Answer::new(ReplyCode::FileNotFound, "No such file or directory"));
         }
     }
     fn cdup(&mut self) {
         let mut ok = false;
         let dir = self.cur_dir.join("..");
-        if let Ok(dir) = canonicalize(dir) {
+        if let Some(dir) = self.resolve_in_jail(&dir) {
             let current_dir = dir;
             self.cur_dir = current_dir;
             ok = true;
         }
         if ok {
             self.send_answer(Answer::new(
-                ResultCode::FileActOk,
+                ReplyCode::FileActionOk,
                 "Change current path successfully",
             ));
         } else {
-            self.send_answer(Answer::new(ResultCode::FileNotFound, "No such file or directory"));
+            self.send_answer(Answer::new(ReplyCode::FileNotFound, "No such file or directory"));
         }
     }
-    fn list(&mut self, path: Option<PathBuf>, add_info: bool) {
-        let path = path.unwrap_or(PathBuf::from("."));
+    fn list(&mut self, path: Option<PathBuf>, style: ListStyle) {
+        let path = self.to_absolute(path.unwrap_or(PathBuf::from(".")));
+        // Checked before the data connection is taken, as for RETR, a refused path
+        // leaves it to the next command
+        if !is_exist(path.as_path().to_str().unwrap_or("")) {
+            self.send_answer(Answer::new(ReplyCode::FileNotFound, "File not found"));
+            return;
+        }
+        if self.resolve_in_jail(&path).is_none() {
+            self.send_answer(Answer::new(ReplyCode::FileNotFound, "Permission denied"));
+            return;
+        }
+        if style == ListStyle::Facts && !path.is_dir() {
+            let message = format!("{} is not a directory", path.display());
+            self.send_answer(Answer::new(ReplyCode::ParamSyntaxError, &message));
+            return;
+        }
         if let Some(mut c) = self.get_data_conn() {
-            let path = self.to_absolute(path);
-            if !is_exist(path.as_path().to_str().unwrap_or("")) {
-                self.send_answer(Answer::new(ResultCode::FileNotFound, "File not found"));
-                return;
-            }
-            self.send_answer(Answer::new(
-                ResultCode::FileStatusOk,
-                "Starting to list directory...",
-            ));
-            let mut out = Vec::new();
-            if path.is_dir() {
-                let dir = Dir::open(path.as_os_str(), OFlag::O_DIRECTORY, Mode::S_IXUSR).unwrap();
-                let mut file_names = dir
-                    .into_iter()
-                    .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
-                    .collect::<Vec<String>>();
-                file_names.sort();
-                file_names = file_names
-                    .iter()
-                    .map(|x| self.cur_dir.join(x).to_string_lossy().to_string())
-                    .collect::<Vec<String>>();
-                if add_info {
-                    file_names.iter().for_each(|s| add_file_info(s, &mut out));
-                } else {
-                    file_names
-                        .iter()
-                        .map(|s| s.split('/').last().unwrap())
-                        .filter(|&s| s.as_bytes()[0] as char != '.')
-                        .for_each(|s| out.extend(format!("{}\r\n", s).as_bytes()));
-                }
+            self.send_answer(Answer::new(ReplyCode::FileStatusOk, "Starting to list directory..."));
+            c.set_transfer_state(TransferState::Sending);
+            let pool = self.listing_pool.clone().filter(|_| self.config.stream_listing);
+            let sent = if let Some(pool) = pool {
+                send_listing_stream(&pool, &mut c, path, style, self.is_admin)
             } else {
-                let path = path.as_os_str().to_str().unwrap();
-                if add_info {
-                    add_file_info(path, &mut out);
-                } else {
-                    out.extend(format!("{}\r\n", path).as_bytes());
+                let out = self.list_output(&path, style);
+                c.set_transfer_total(out.len() as u64);
+                let sent = c.send(&out);
+                if sent {
+                    c.add_transferred(out.len());
                 }
-            }
-            c.send(&out);
+                sent
+            };
             c.shutdown();
-            self.send_answer(Answer::new(ResultCode::CloseDataClose, "Directory send Ok"));
+            finish_transfer(&mut c, sent);
+            if sent {
+                self.send_answer(Answer::new(ReplyCode::ClosingDataConn, "Directory send Ok"));
+            } else {
+                let message = "Connection closed; transfer aborted";
+                self.send_answer(Answer::new(ReplyCode::ConnClosed, message));
+            }
+        } else {
+            self.send_answer(Answer::new(ReplyCode::ConnClosed, "No opened data connection"));
+        }
+    }
+    // The listing lines, each one is ended with CRLF. The names aren't always
+    // UTF-8: NLST sends their raw bytes, LIST the lossy text, MLSD percent-encodes them.
+    fn list_output(&self, path: &Path, style: ListStyle) -> Vec<u8> {
+        let mut out = Vec::new();
+        // Writing to a Vec doesn't fail
+        let _ = write_listing(path, style, self.is_admin, &mut out);
+        out
+    }
+    fn pasv_port(&self) -> u16 {
+        if let Some(port) = self.data_port {
+            port + 2
         } else {
-            self.send_answer(Answer::new(ResultCode::ConnClose, "No opened data connection"));
+            22
         }
     }
     fn pasv(&mut self) {
+        // The 227 reply has room for an IPv4 address only
+        if let Some(IpAddr::V6(ip)) = self.cmd_conn.peer_ip() {
+            if ip.to_ipv4_mapped().is_none() {
+                let message = "Can't use PASV on an IPv6 connection, use EPSV";
+                self.send_answer(Answer::new(ReplyCode::CantOpenDataConn, message));
+                return;
+            }
+        }
         self.pasv_enable = true;
-        let port = if let Some(port) = self.data_port { port + 2 } else { 22 };
-        let message = format!("Entering Passive Mode (127,0,0,1,{},{})", port >> 8, port & 0xFF);
+        let port = self.pasv_port();
+        // The client connects to the address it reached the server on
+        let ip = match self.cmd_conn.local_ip() {
+            Some(IpAddr::V4(ip)) => ip,
+            Some(IpAddr::V6(ip)) => ip.to_ipv4_mapped().unwrap_or(Ipv4Addr::LOCALHOST),
+            None => Ipv4Addr::LOCALHOST,
+        };
+        let [a, b, c, d] = ip.octets();
+        let message = format!(
+            "Entering Passive Mode ({},{},{},{},{},{})",
+            a,
+            b,
+            c,
+            d,
+            port >> 8,
+            port & 0xFF
+        );
         let addr = format!("0.0.0.0:{}", port);
-        // TODO: Add connection fail handler
-        let listener = TcpListener::bind(&addr).unwrap();
-        let listener = Socket(listener.as_raw_fd());
-        self.send_answer(Answer::new(ResultCode::PassMode, &message));
-        let mut s = Acceptor::accept(listener.as_raw_fd());
-        s.set_keep_alive(true);
-        s.set_no_delay(true);
-        s.set_reuse_addr(true);
-        s.set_reuse_port(true);
-        self.data_conn = Some(Connection::new(s));
-    }
-    fn port(&mut self, port: u16) {
+        // The client connects before the transfer command, it's accepted by the transfer
+        if let Err(e) = self.data.listen(&addr) {
+            warn!("Couldn't listen passive port {}: {}", port, e);
+            let message = "Can't open passive connection";
+            self.send_answer(Answer::new(ReplyCode::CantOpenDataConn, message));
+            return;
+        }
+        self.send_answer(Answer::new(ReplyCode::EnteringPassiveMode, &message));
+    }
+    fn stat(&mut self, path: Option<PathBuf>) {
+        match path {
+            None => {
+                let message = self.status_message();
+                self.send_answer(Answer::new(ReplyCode::SystemStatus, &message));
+            }
+            Some(path) => {
+                let path = self.to_absolute(path);
+                if !is_exist(path.to_str().unwrap_or("")) {
+                    self.send_answer(Answer::new(ReplyCode::FileNotFound, "File not found"));
+                    return;
+                }
+                let out = self.list_output(&path, ListStyle::Long);
+                let mut message = format!("Status of {}:\r\n", path.display());
+                message.push_str(&String::from_utf8_lossy(&out));
+                message.push_str("End of status");
+                let code =
+                    if path.is_dir() { ReplyCode::DirectoryStatus } else { ReplyCode::FileStatus };
+                self.send_answer(Answer::new(code, &message));
+            }
+        }
+    }
+    fn status_message(&self) -> String {
+        let mut lines = vec!["FTP server status:".to_string()];
+        lines.push(format!("Connected to {}", self.peer_name()));
+        if let Some(name) = &self.name {
+            lines.push(format!("Logged in as {}", name));
+        }
+        lines.push(format!("TYPE: {}", self.transfer_type));
+        if self.pasv_enable {
+            lines.push(format!("Data connection: passive, port {}", self.pasv_port()));
+        } else {
+            let port = self.data_port.unwrap_or(22);
+            let addr = self.data_addr.unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], port)));
+            lines.push(format!("Data connection: active, address {}", addr));
+        }
+        lines.push("Protection level: Clear".to_string());
+        let state = self.transfer.get();
+        if state.is_active() {
+            let (bytes, total) = self.transfer.progress();
+            match total {
+                Some(total) => {
+                    lines.push(format!("Transfer: {:?} {} of {} bytes", state, bytes, total))
+                }
+                None => lines.push(format!("Transfer: {:?} {} bytes", state, bytes)),
+            }
+        } else {
+            lines.push("No data transfer in progress".to_string());
+        }
+        lines.push("End of status".to_string());
+        lines.join("\r\n")
+    }
+    // Serve the control connection between the chunks of a transfer, only STAT and
+    // ABOR are answered until the transfer is over. Another command stays buffered
+    // with the ones behind it, they're handled after the transfer in order.
+    // Return true if the client aborts the transfer.
+    fn poll_control(&mut self) -> bool {
+        if self.cmd_conn.poll_urgent() {
+            self.synch();
+        }
+        if self.accepts_input() && self.cmd_conn.poll_readable() {
+            // Readable with nothing to read, the client closed the control connection
+            if !matches!(self.cmd_conn.fill_input(), Some(n) if n > 0) {
+                self.control_closed();
+                return true;
+            }
+        }
+        while let Some(msg) = self.cmd_conn.peek_line() {
+            match Command::parse(&strip_telnet(msg.strip_suffix(b"\r\n").unwrap_or(msg))) {
+                Ok(Command::Stat(None)) => {
+                    self.cmd_conn.next_line();
+                    let message = self.status_message();
+                    self.send_answer(Answer::new(ReplyCode::SystemStatus, &message));
+                }
+                Ok(Command::Abort) => {
+                    self.cmd_conn.next_line();
+                    return true;
+                }
+                _ => break,
+            }
+        }
+        false
+    }
+    // The TELNET synch: the urgent DM is taken out of the stream, the ABOR follows it
+    // in-band. Without it, the pending urgent byte would keep the poll waking up.
+    fn synch(&mut self) {
+        if self.cmd_conn.recv_urgent().is_some() {
+            debug!("[{}] TELNET synch from {}", self.cmd_conn.id(), self.peer_name());
+        }
+    }
+    // The transfer of a gone session is aborted, its data side is released at once
+    fn control_closed(&mut self) {
+        info!("[{}] Control connection closed during the transfer", self.cmd_conn.id());
+        self.shutdown();
+    }
+    // Wait for the next chunk of an upload without blocking the control connection.
+    // A stalled client gets data_connect_timeout, as for opening the connection.
+    fn wait_data(&mut self, data: &Connection) -> DataWait {
+        let deadline = Instant::now() + Duration::from_secs(self.config.data_connect_timeout);
+        loop {
+            if self.backlog() > 0 && self.poll_control() {
+                return DataWait::Aborted;
+            }
+            // A full backlog isn't read, the control connection would wake the poll up
+            let cmd_flags = if self.accepts_input() {
+                PollFlags::POLLIN | PollFlags::POLLPRI
+            } else {
+                PollFlags::POLLPRI
+            };
+            let mut fds = [
+                PollFd::new(data.get_fd().0, PollFlags::POLLIN),
+                PollFd::new(self.cmd_conn.get_fd().0, cmd_flags),
+            ];
+            let timeout = deadline.saturating_duration_since(Instant::now()).as_millis();
+            match poll(&mut fds, timeout.min(i32::MAX as u128) as i32) {
+                Ok(0) => return DataWait::TimedOut,
+                Ok(_) => (),
+                Err(Errno::EINTR) => continue,
+                Err(_) => return DataWait::Ready,
+            }
+            let data_ready = fds[0].revents().is_some_and(|r| !r.is_empty());
+            let cmd_events = fds[1].revents().unwrap_or_else(PollFlags::empty);
+            if cmd_events.intersects(PollFlags::POLLHUP | PollFlags::POLLERR) {
+                self.control_closed();
+                return DataWait::Aborted;
+            }
+            if cmd_events.intersects(PollFlags::POLLIN | PollFlags::POLLPRI) && self.poll_control()
+            {
+                return DataWait::Aborted;
+            }
+            if data_ready {
+                return DataWait::Ready;
+            }
+        }
+    }
+    fn port(&mut self, addr: SocketAddrV4) {
+        let port = addr.port();
+        // Connecting to the well-known ports of client host is the bounce attack
+        if self.config.reject_privileged_data_ports && port < 1024 {
+            let message = format!("PORT {} rejected, data port can't be less than 1024", port);
+            self.send_answer(Answer::new(ReplyCode::ParamSyntaxError, &message));
+            return;
+        }
+        let mut data_addr = SocketAddr::V4(addr);
+        if let Some(peer_ip) = self.cmd_conn.peer_ip() {
+            if !same_ip(&peer_ip, addr.ip()) {
+                if !self.config.ignore_port_ip {
+                    let message =
+                        format!("PORT {} rejected, it isn't the client address", addr.ip());
+                    self.send_answer(Answer::new(ReplyCode::ParamSyntaxError, &message));
+                    return;
+                }
+                // The client behind NAT reports its private ip
+                data_addr = SocketAddr::new(peer_ip, port);
+                info!("PORT {} from {} is replaced by {}", addr, self.peer_name(), data_addr);
+            }
+        }
         self.pasv_enable = false;
         self.data_port = Some(port);
+        self.data_addr = Some(data_addr);
         let message = format!("PORT command successful, data port is now {}", port);
-        self.send_answer(Answer::new(ResultCode::Ok, &message));
+        self.send_answer(Answer::new(ReplyCode::CommandOk, &message));
     }
     fn size(&mut self, path: PathBuf) {
         let mut size = None;
@@ -525,74 +1157,226 @@ impl Session {
         }
         if let Some(size) = size {
             let message = format!("{}", size);
-            self.send_answer(Answer::new(ResultCode::FileStatus, &message));
+            self.send_answer(Answer::new(ReplyCode::FileStatus, &message));
         } else {
-            self.send_answer(Answer::new(ResultCode::FileNotFound, "Could not get file size."));
+            self.send_answer(Answer::new(ReplyCode::FileNotFound, "Could not get file size."));
+        }
+    }
+    // 213 YYYYMMDDHHMMSS, the UTC modification time
+    fn mdtm(&mut self, path: PathBuf) {
+        match self.resolve_in_jail(&path).and_then(|path| stat(&path).ok()) {
+            Some(st) if is_regular_mode(st.st_mode) => {
+                let time = NaiveDateTime::from_timestamp(st.st_mtime, 0);
+                let message = time.format("%Y%m%d%H%M%S").to_string();
+                self.send_answer(Answer::new(ReplyCode::FileStatus, &message));
+            }
+            _ => self.send_answer(Answer::new(ReplyCode::FileNotFound, "Could not get file time.")),
+        }
+    }
+    // MFMT time path, or MDTM time path with mdtm_set. It's allowed to the users who
+    // can upload, e.g. to keep the time of a file right after its STOR.
+    fn set_mtime(&mut self, time: NaiveDateTime, path: PathBuf) {
+        let target = match self.resolve_in_jail(&path) {
+            Some(target) if self.is_admin && is_regular(target.to_str().unwrap_or("")) => target,
+            _ => {
+                let message = format!("Couldn't set the time of {}", path.display());
+                self.send_answer(Answer::new(ReplyCode::FileNotFound, &message));
+                return;
+            }
+        };
+        // timestamp_nanos() overflows after 2262, the seconds cover any 4-digit year
+        let mtime = TimeSpec::from(nix::libc::timespec {
+            tv_sec: time.timestamp(),
+            tv_nsec: time.timestamp_subsec_nanos() as i64,
+        });
+        let atime =
+            TimeSpec::from(nix::libc::timespec { tv_sec: 0, tv_nsec: nix::libc::UTIME_OMIT });
+        match utimensat(None, &target, &atime, &mtime, UtimensatFlags::FollowSymlink) {
+            Ok(()) => {
+                let time = time.format("%Y%m%d%H%M%S");
+                let message = format!("Modify={}; {}", time, path.display());
+                self.send_answer(Answer::new(ReplyCode::FileStatus, &message));
+            }
+            Err(e) => {
+                warn!("Set the time of {:?} failed: {}", target, e);
+                let message = format!("Couldn't set the time of {}", path.display());
+                self.send_answer(Answer::new(ReplyCode::FileNotFound, &message));
+            }
         }
     }
     fn pwd(&mut self) {
         let message = format!("{}", self.cur_dir.to_str().unwrap_or(""));
         if !message.is_empty() {
             let msg = format!("\"{}\"", message);
-            self.send_answer(Answer::new(ResultCode::CreatPath, msg.as_str()));
+            self.send_answer(Answer::new(ReplyCode::PathCreated, msg.as_str()));
         } else {
-            self.send_answer(Answer::new(ResultCode::FileNotFound, "No such file or directory"));
+            self.send_answer(Answer::new(ReplyCode::FileNotFound, "No such file or directory"));
         }
     }
+    // Log out and restore the defaults, the control connection stays open
+    fn rein(&mut self) {
+        self.data.close();
+        self.name = None;
+        self.login = None;
+        self.waiting_password = false;
+        self.is_admin = false;
+        self.cur_dir = self.server_root.clone();
+        self.file_name = None;
+        self.transfer_type = TransferType::BINARY;
+        self.pasv_enable = self.config.pasv_enable;
+        self.data_port = Some(22);
+        self.data_addr = None;
+        self.resume_point = 0;
+        self.transfer.reset();
+        // The connection is kept as it is: the replies still queued, and the commands
+        // sent after the REIN are for the new login
+        self.send_answer(Answer::from_code(ReplyCode::ServiceReady));
+    }
     fn quit(&mut self) {
-        self.send_answer(Answer::new(ResultCode::ServiceCloseCtlCon, "Goodbye"));
+        self.login = None;
+        self.send_answer(Answer::new(ReplyCode::ServiceClosing, "Goodbye"));
+        self.cmd_conn.drain_output(DRAIN_TIMEOUT);
         self.cmd_conn.shutdown();
     }
-    fn retr(&mut self, path: PathBuf) {
+    // None if no transfer started, e.g. the file couldn't be opened
+    fn retr(&mut self, path: PathBuf) -> Option<TransferResult> {
         // 21863760 bytes received in 0.30 secs (70.3109 MB/s)
+        let file = path.clone();
+        let path = path.to_str().unwrap();
+        // Opened before the data connection, a denied read is a clean 550 instead of
+        // a 150 followed by a failure
+        let fd = match self.open_download(path).and_then(|fd| self.check_binary(fd, path)) {
+            Ok(fd) => fd,
+            Err(answer) => {
+                self.send_answer(answer);
+                return None;
+            }
+        };
+        // The size of the opened file, the path may be replaced meanwhile
+        let size = match fstat(fd) {
+            Ok(stat) => stat.st_size as usize,
+            Err(e) => {
+                warn!("Can't stat file {}: {}", path, e);
+                self.fs.close(fd).unwrap_or_default();
+                let message = format!("Couldn't read file {}", path);
+                self.send_answer(Answer::new(ReplyCode::LocalError, &message));
+                return None;
+            }
+        };
         if let Some(mut c) = self.get_data_conn() {
-            let path = path.to_str().unwrap();
             let mode = self.transfer_type;
-            let mut ok = false;
-            if is_exist(path) && is_regular(path) && self.is_admin {
-                let message = format!("Opening {} mode data connection for {}", mode, &path);
-                self.send_answer(Answer::new(ResultCode::FileStatusOk, &message));
-                let instant = Instant::now();
-                if let Ok(fd) = open(path, OFlag::O_RDWR, Mode::S_IRUSR) {
-                    ok = true;
-                    let size = lstat(path).unwrap().st_size as usize;
-                    let mut barrier = SpeedBarrier::new(self.config.max_speed);
-                    let mut len = 0usize;
-                    loop {
-                        match c.send_file(None, fd, Some(len as i64), size) {
-                            Some(0) => break,
-                            Some(n) => {
-                                len += n;
-                                if n < DEAFULT_SEND_SIZE {
-                                    break;
-                                }
-                                barrier.limit_speed(n);
-                            }
-                            None => {
-                                warn!("Can't send file {}", path);
-                                break;
-                            }
+            let message = format!("Opening {} mode data connection for {}", mode, &path);
+            self.send_answer(Answer::new(ReplyCode::FileStatusOk, &message));
+            let instant = Instant::now();
+            c.set_transfer_state(TransferState::Sending);
+            c.set_transfer_total(size as u64);
+            let mut barrier = SpeedBarrier::new(self.config.max_speed);
+            let mut len = 0usize;
+            let mut answer = None;
+            let mut aborted = false;
+            let mut copy_buf = Vec::new();
+            while len < size {
+                let chunk = DEAFULT_SEND_SIZE.min(size - len);
+                let sent = if self.config.force_userspace_transfer {
+                    self.copy_chunk(&mut c, fd, chunk, &mut copy_buf)
+                } else {
+                    c.send_file(None, fd, Some(len as i64), chunk)
+                };
+                match sent {
+                    Some(0) => {
+                        warn!("File {} is truncated at {}", path, len);
+                        let message = format!("Couldn't read file {}", path);
+                        answer = Some(Answer::new(ReplyCode::LocalError, &message));
+                        break;
+                    }
+                    Some(n) => {
+                        len += n;
+                        c.add_transferred(n);
+                        barrier.limit_speed(n);
+                        if self.poll_control() {
+                            aborted = true;
+                            let message = "Transfer aborted";
+                            answer = Some(Answer::new(ReplyCode::ConnClosed, message));
+                            break;
                         }
                     }
-                    c.shutdown();
-                    let message = format!("Transfer {} complete", path);
-                    self.send_answer(Answer::new(ResultCode::CloseDataClose, &message));
-                    info!("Transfer {} complete", path);
-                    let elapsed = instant.elapsed().as_secs_f64();
-                    let size = format_size(len as f64 / elapsed);
-                    info!("{} bytes send in {:.2} secs ({}B/s)", len, elapsed, size);
-                    info!("-> file transfer done!");
+                    None => {
+                        warn!("Can't send file {}", path);
+                        let message = "Connection closed; transfer aborted";
+                        answer = Some(Answer::new(ReplyCode::ConnClosed, message));
+                        break;
+                    }
                 }
             }
-            if !ok {
-                self.send_answer(Answer::new(
-                    ResultCode::FileNotFound,
-                    &format!("Failed to open file {}, please check file", path),
-                ));
+            self.fs.close(fd).unwrap_or_default();
+            c.shutdown();
+            finish_transfer(&mut c, answer.is_none());
+            let result = TransferResult {
+                bytes: len as u64,
+                duration: instant.elapsed(),
+                direction: Direction::Download,
+                path: file,
+                outcome: transfer_outcome(&answer, aborted),
+            };
+            self.transfer_done(&result);
+            let mut message = format!("Transfer {} complete", path);
+            if self.config.transfer_summary {
+                message.push_str(&transfer_summary(result.bytes, result.duration));
+            }
+            let answer =
+                answer.unwrap_or_else(|| Answer::new(ReplyCode::ClosingDataConn, &message));
+            // Nobody to answer if the client is gone
+            if self.cmd_conn.connected() {
+                self.send_answer(answer);
+                if aborted {
+                    self.send_answer(Answer::new(ReplyCode::ClosingDataConn, "ABOR successful"));
+                }
             }
             c.shutdown();
+            Some(result)
         } else {
-            self.send_answer(Answer::new(ResultCode::ConnClose, "No opened data connection"));
+            self.fs.close(fd).unwrap_or_default();
+            self.send_answer(Answer::new(ReplyCode::ConnClosed, "No opened data connection"));
+            None
+        }
+    }
+    // TYPE A on a binary file is likely a misconfigured client. The first block is
+    // read at its offset, the transfer still starts from the beginning.
+    fn check_binary(&self, fd: RawFd, path: &str) -> Result<RawFd, Answer> {
+        let guard = self.config.ascii_binary_guard;
+        if guard == BinaryGuard::Off || self.transfer_type != TransferType::ASCII {
+            return Ok(fd);
+        }
+        let mut block = [0u8; BINARY_CHECK_SIZE];
+        let n = retry_eintr(|| pread(fd, &mut block, 0)).unwrap_or(0);
+        if !looks_binary(&block[..n]) {
+            return Ok(fd);
+        }
+        warn!("TYPE A RETR of the binary file {} from {}", path, self.peer_name());
+        if guard == BinaryGuard::Warn {
+            return Ok(fd);
+        }
+        self.fs.close(fd).unwrap_or_default();
+        Err(Answer::new(ReplyCode::FileNotFound, "File is binary, use TYPE I"))
+    }
+    // The file of a RETR opened for reading, or the 550 reply
+    fn open_download(&self, path: &str) -> Result<RawFd, Answer> {
+        let failed = || {
+            let message = format!("Failed to open file {}, please check file", path);
+            Answer::new(ReplyCode::FileNotFound, &message)
+        };
+        if !(is_exist(path) && is_regular(path) && self.is_admin) {
+            return Err(failed());
+        }
+        match self.fs.open(Path::new(path), OFlag::O_RDONLY, Mode::empty()) {
+            Ok(fd) => Ok(fd),
+            Err(Errno::EACCES | Errno::EPERM) => {
+                Err(Answer::new(ReplyCode::FileNotFound, "Permission denied"))
+            }
+            Err(e) => {
+                warn!("Open {} for RETR failed: {}", path, e);
+                Err(failed())
+            }
         }
     }
     // example:
@@ -601,19 +1385,24 @@ impl Session {
     // 150 Opening BINARY mode data connection for miniftp (21863760 bytes).
     // 226 Transfer complete.
     // 21863760 bytes received in 10.81 secs (1.9284 MB/s)
-    fn stor(&mut self, path: PathBuf) {
+    fn stor(&mut self, path: PathBuf) -> Option<TransferResult> {
         if let Some(mut c) = self.get_data_conn() {
             // check file path and admin
 
             if self.is_admin {
+                let fd = match self.open_upload(&path) {
+                    Ok(fd) => fd,
+                    Err(message) => {
+                        c.shutdown();
+                        self.send_answer(Answer::new(ReplyCode::FileNotFound, &message));
+                        return None;
+                    }
+                };
                 self.send_answer(Answer::new(
-                    ResultCode::DataConnOpened,
+                    ReplyCode::DataConnAlreadyOpen,
                     "Starting to receive file...",
                 ));
                 let path = path.to_str().unwrap();
-                let oflag: OFlag = OFlag::O_CREAT | OFlag::O_RDWR;
-
-                let fd = open(path, oflag, Mode::from_bits(DEAFULT_FILE_PERM).unwrap()).unwrap();
                 let _lock = FileLock::new(fd).lock(true);
                 if self.resume_point <= 0 {
                     ftruncate(fd, 0).expect("Couldn't ftruncate file at 0");
@@ -627,44 +1416,187 @@ impl Session {
                 let instant = Instant::now();
                 let mut len = 0usize;
                 let mut barrier = SpeedBarrier::new(self.config.max_speed);
-                loop {
-                    let buf = c.read_buf();
+                let mut answer = None;
+                let mut ascii = match self.transfer_type {
+                    TransferType::ASCII => Some(AsciiTranslator::new()),
+                    _ => None,
+                };
+                let mut eof = false;
+                let mut aborted = false;
+                c.set_transfer_state(TransferState::Receiving);
+                while !eof {
+                    match self.wait_data(&c) {
+                        DataWait::Ready => (),
+                        DataWait::Aborted => {
+                            aborted = true;
+                            answer = Some(Answer::new(ReplyCode::ConnClosed, "Transfer aborted"));
+                            break;
+                        }
+                        DataWait::TimedOut => {
+                            let message = "Data connection timed out; transfer aborted";
+                            answer = Some(Answer::new(ReplyCode::ConnClosed, message));
+                            break;
+                        }
+                    }
+                    let received = match c.recv() {
+                        Some(buf) => buf,
+                        None => {
+                            let message = "Connection closed; transfer aborted";
+                            answer = Some(Answer::new(ReplyCode::ConnClosed, message));
+                            break;
+                        }
+                    };
+                    eof = received.is_empty();
+                    let wire_len = received.len();
+                    let buf = match ascii.as_mut() {
+                        Some(translator) if eof => translator.finish(),
+                        Some(translator) => translator.translate_in(&received),
+                        None => received,
+                    };
                     if buf.is_empty() {
-                        break;
+                        continue;
                     }
-                    match write(fd, &buf) {
-                        Ok(0) | Err(_) => break,
-                        Ok(n) => {
+                    match self.fs.write(fd, &buf) {
+                        Ok(n) if n == buf.len() => {
                             len += n;
-                            debug!("Receive data {}", buf.len());
+                            c.add_transferred(wire_len);
+                            debug!("Receive data {}", wire_len);
+                        }
+                        Err(Errno::ENOSPC) | Err(Errno::EDQUOT) => {
+                            let message = "Insufficient storage space";
+                            answer = Some(Answer::new(ReplyCode::InsufficientStorage, message));
+                            break;
+                        }
+                        result => {
+                            warn!("Couldn't write file {}: {:?}", path, result);
+                            let message = format!("Couldn't write file {}", path);
+                            answer = Some(Answer::new(ReplyCode::LocalError, &message));
+                            break;
                         }
                     }
-                    barrier.limit_speed(buf.len());
+                    barrier.limit_speed(wire_len);
+                }
+                if answer.is_none()
+                    && self.config.fsync_on_close
+                    && !self.sync_file(fd, Path::new(path))
+                {
+                    let message = format!("Couldn't sync file {} to disk", path);
+                    answer = Some(Answer::new(ReplyCode::LocalError, &message));
                 }
-                close(fd).unwrap();
-                let elapsed = instant.elapsed().as_secs_f64();
-                let size = format_size(len as f64 / elapsed);
-                info!("{} bytes received in {:.2} secs ({}B/s)", len, elapsed, size);
+                self.fs.close(fd).unwrap();
                 c.shutdown();
-                self.send_answer(Answer::new(
-                    ResultCode::CloseDataClose,
-                    &format!("Transfer file {} done", path),
-                ));
+                finish_transfer(&mut c, answer.is_none());
+                let result = TransferResult {
+                    bytes: len as u64,
+                    duration: instant.elapsed(),
+                    direction: Direction::Upload,
+                    path: PathBuf::from(path),
+                    outcome: transfer_outcome(&answer, aborted),
+                };
+                self.transfer_done(&result);
+                if answer.is_some() && self.config.delete_partial_upload {
+                    info!("Delete partial upload {}", path);
+                    unlink(path).unwrap_or_default();
+                }
+                let mut message = format!("Transfer file {} done", path);
+                if self.config.transfer_summary {
+                    message.push_str(&transfer_summary(result.bytes, result.duration));
+                }
+                if self.cmd_conn.connected() {
+                    self.send_answer(
+                        answer.unwrap_or_else(|| Answer::new(ReplyCode::ClosingDataConn, &message)),
+                    );
+                    if aborted {
+                        self.send_answer(Answer::new(
+                            ReplyCode::ClosingDataConn,
+                            "ABOR successful",
+                        ));
+                    }
+                }
+                Some(result)
             } else {
                 c.shutdown();
-                self.send_answer(Answer::new(ResultCode::FileNotFound, "Couldn't open file"));
+                self.send_answer(Answer::new(ReplyCode::FileNotFound, "Couldn't open file"));
+                None
             }
         } else {
-            self.send_answer(Answer::new(ResultCode::DataConnFail, "No opened data connection"));
+            self.send_answer(Answer::new(ReplyCode::CantOpenDataConn, "No opened data connection"));
+            None
         }
     }
-    fn help(&mut self, content: String) {
-        if self.help_map.contains_key(&content.as_str()) {
-            let message = self.help_map[&content.as_str()];
-            self.send_answer(Answer::new(ResultCode::HelpMsg, &message));
-        } else {
+    // The log line, the metrics of the completed transfers and the observers, before
+    // the final reply so that they're settled once the client sees it
+    fn transfer_done(&self, result: &TransferResult) {
+        info!("{}", result);
+        if result.is_complete() {
+            self.metrics.record(result.bytes, result.duration);
+        }
+        for observer in self.observers.iter() {
+            observer.transfer_done(result);
+        }
+    }
+    // Read a chunk of the file and write it to the data connection, the path without
+    // sendfile. None if the file or the connection fails, Some(0) at the end of the file.
+    fn copy_chunk(
+        &self,
+        c: &mut Connection,
+        fd: RawFd,
+        chunk: usize,
+        buf: &mut Vec<u8>,
+    ) -> Option<usize> {
+        buf.resize(chunk, 0);
+        match self.fs.read(fd, buf) {
+            Ok(n) if n == 0 || c.send(&buf[..n]) => Some(n),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Read file error: {}", e);
+                None
+            }
+        }
+    }
+    // Only a regular file is written, a FIFO or a device target would block or harm the server
+    fn open_upload(&self, path: &Path) -> Result<RawFd, String> {
+        let refused = || format!("{} is not a regular file", path.display());
+        if let Ok(st) = stat(path) {
+            if !is_regular_mode(st.st_mode) {
+                return Err(refused());
+            }
+        }
+        // O_NONBLOCK: a FIFO created meanwhile doesn't wait for a reader, it's refused by fstat
+        let oflag = OFlag::O_CREAT | OFlag::O_RDWR | OFlag::O_NONBLOCK;
+        let mode = Mode::from_bits(DEAFULT_FILE_PERM).unwrap();
+        let fd = self.fs.open(path, oflag, mode).map_err(|e| {
+            warn!("Couldn't open file {:?}: {}", path, e);
+            "Couldn't open file".to_string()
+        })?;
+        match fstat(fd) {
+            Ok(st) if is_regular_mode(st.st_mode) => Ok(fd),
+            _ => {
+                self.fs.close(fd).unwrap_or_default();
+                Err(refused())
+            }
+        }
+    }
+    // Like the whole command, it's running in the worker pool rather than the event loop
+    fn sync_file(&self, fd: i32, path: &Path) -> bool {
+        let mut result = self.fs.fsync(fd);
+        if result.is_ok() && self.config.fsync_dir {
+            if let Some(dir) = path.parent() {
+                result = self.fs.fsync_dir(dir);
+            }
+        }
+        if let Err(e) = result {
+            warn!("Couldn't fsync file {:?}, Err: {}", path, e);
+        }
+        result.is_ok()
+    }
+    fn help(&mut self, content: String) {
+        if self.help_map.contains_key(&content.as_str()) {
+            let message = self.help_map[&content.as_str()];
+            self.send_answer(Answer::new(ReplyCode::HelpMessage, &message));
+        } else {
             self.send_answer(Answer::new(
-                ResultCode::SyntaxErr,
+                ReplyCode::SyntaxError,
                 &format!("?Invalid help command {}", content),
             ));
         }
@@ -683,6 +1615,9 @@ impl Session {
             ("del", "del remote-file - delete a file"),
             ("binary", "binary - set binary transfer type"),
             ("size", "size remote-file - show size of remote file"),
+            ("modtime", "modtime remote-file - show last modification time (MDTM path)"),
+            ("mfmt", "MFMT YYYYMMDDHHMMSS path - set the modification time, in UTC"),
+            ("mlsd", "MLSD [ remote-directory ] - list a directory with the facts of RFC 3659"),
             ("stat", "stat [ remote-file ] - print server information"),
             ("syst", "syst - show remote system type"),
             ("noop", "noop - no operation"),
@@ -691,13 +1626,53 @@ impl Session {
             ("exit", "exit - exit program"),
         ])
     }
+    // The configured reply is used only if it agrees with the LIST format
+    fn syst(&mut self) {
+        let system = self.list_format.system_type();
+        let reply = &self.config.syst_reply;
+        let message = if reply.split(' ').next() == Some(system) {
+            reply.clone()
+        } else {
+            warn!("SYST reply {:?} contradicts the {} listing", reply, system);
+            ReplyCode::SystemType.default_message().to_string()
+        };
+        self.send_answer(Answer::new(ReplyCode::SystemType, &message));
+    }
+    // The handlers of OPTS by option name, a feature with options registers its handler here
+    fn get_opts_map() -> HashMap<&'static str, OptsHandler> {
+        HashMap::from([("UTF8", Self::opts_utf8 as OptsHandler)])
+    }
+    fn opts(&mut self, option: String) {
+        let (name, params) = option.split_once(' ').unwrap_or((&option, ""));
+        let answer = match self.opts_map.get(name) {
+            Some(handler) => handler(self, params),
+            None => Answer::new(ReplyCode::ParamSyntaxError, "Option not understood"),
+        };
+        self.send_answer(answer);
+    }
+    // The names are sent as they are on disk, UTF-8 can't be turned off
+    fn opts_utf8(&mut self, params: &str) -> Answer {
+        match params {
+            "" | "ON" => Answer::new(ReplyCode::CommandOk, "Always in UTF8 mode"),
+            _ => Answer::new(ReplyCode::ParamSyntaxError, "Option not understood"),
+        }
+    }
     fn abort(&mut self) {
-        self.send_answer(Answer::new(ResultCode::CloseDataClose, "No transfer to Abort!"));
+        self.send_answer(Answer::new(ReplyCode::ClosingDataConn, "No transfer to Abort!"));
     }
     fn send_answer(&mut self, answer: Answer) {
+        match answer.code {
+            ReplyCode::SyntaxError
+            | ReplyCode::ParamSyntaxError
+            | ReplyCode::CommandNotImplemented
+            | ReplyCode::NotLoggedIn => self.failed_commands += 1,
+            code if code.code() < 400 => self.failed_commands = 0,
+            _ => (),
+        }
         let mut buf = Vec::new();
         self.codec.encode(answer.clone(), &mut buf).unwrap();
-        self.cmd_conn.send(&buf);
+        // Queued as a whole, a multi-line reply isn't split by the next one
+        self.cmd_conn.send_queued(&buf);
         debug!("{} {}", answer, buf.len());
     }
 }
@@ -705,7 +1680,36 @@ impl Session {
 impl Drop for Session {
     fn drop(&mut self) {
         // let fd = self.cmd_conn.get_fd();
-        self.cmd_conn.shutdown();
+        self.shutdown();
+    }
+}
+
+// Done before the final reply, so the state is settled once the client sees it
+fn finish_transfer(conn: &mut Connection, ok: bool) {
+    if !ok {
+        conn.set_transfer_state(TransferState::Aborting);
+    }
+    conn.set_transfer_state(TransferState::Done);
+}
+
+fn transfer_outcome(failure: &Option<Answer>, aborted: bool) -> TransferOutcome {
+    match failure {
+        None => TransferOutcome::Complete,
+        Some(_) if aborted => TransferOutcome::Aborted,
+        Some(_) => TransferOutcome::Failed,
+    }
+}
+
+// The file type bits, is_reg! would take a socket for a regular file
+fn is_regular_mode(mode: u32) -> bool {
+    SFlag::from_bits_truncate(mode) & SFlag::S_IFMT == SFlag::S_IFREG
+}
+
+// The IPv4-mapped address of a dual stack socket is the same client
+fn same_ip(peer: &IpAddr, ip: &Ipv4Addr) -> bool {
+    match peer {
+        IpAddr::V4(peer) => peer == ip,
+        IpAddr::V6(peer) => peer.to_ipv4_mapped().as_ref() == Some(ip),
     }
 }
 
@@ -718,6 +1722,23 @@ fn invaild_path(path: &Path) -> bool {
     true
 }
 
+// Whether a component of the relative path under base is a symlink
+fn has_symlink(relative: &Path, base: &Path) -> bool {
+    let mut path = base.to_path_buf();
+    relative.components().any(|component| {
+        path.push(component);
+        lstat(&path)
+            .is_ok_and(|st| SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFLNK)
+    })
+}
+
+// Bytes length of the argument behind the command verb, line terminator excluded
+fn argument_len(line: &[u8]) -> Option<usize> {
+    let line = line.strip_suffix(b"\r\n").unwrap_or(line);
+    let index = line.iter().position(|&b| b == b' ')?;
+    Some(line.len() - index - 1)
+}
+
 pub fn permissions(mode: u32) -> String {
     let mut out = b"wrxwrxwrx".to_vec();
     for i in 0..9 {
@@ -728,16 +1749,190 @@ pub fn permissions(mode: u32) -> String {
     String::from_utf8(out).unwrap()
 }
 
+// Write the listing line by line. The names of a directory are read whole to be
+// sorted, only their lines aren't buffered. writable is whether the user may change
+// the files, for the MLSD perm facts.
+fn write_listing(
+    path: &Path,
+    style: ListStyle,
+    writable: bool,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut line = Vec::new();
+    if style == ListStyle::Facts {
+        // The directory itself and its parent come first, "." and ".." aren't repeated
+        add_file_facts(path, "cdir", b".", writable, &mut line);
+        add_file_facts(&path.join(".."), "pdir", b"..", writable, &mut line);
+        out.write_all(&line)?;
+    }
+    if path.is_dir() {
+        let dir = match Dir::open(path.as_os_str(), OFlag::O_DIRECTORY, Mode::S_IXUSR) {
+            Ok(dir) => dir,
+            Err(e) => {
+                warn!("Couldn't open directory {:?}: {}", path, e);
+                return Ok(());
+            }
+        };
+        let mut file_names = dir
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| OsStr::from_bytes(entry.file_name().to_bytes()).to_os_string())
+            .collect::<Vec<OsString>>();
+        file_names.sort();
+        for name in file_names {
+            line.clear();
+            match style {
+                ListStyle::Long => add_file_info(&path.join(&name), &mut line),
+                ListStyle::Facts if name != "." && name != ".." => {
+                    add_file_facts(&path.join(&name), "", name.as_bytes(), writable, &mut line)
+                }
+                ListStyle::Names if !name.as_bytes().starts_with(b".") => {
+                    line.extend(name.as_bytes());
+                    line.extend(b"\r\n");
+                }
+                _ => (),
+            }
+            out.write_all(&line)?;
+        }
+    } else if style == ListStyle::Long {
+        add_file_info(path, &mut line);
+        out.write_all(&line)?;
+    } else {
+        out.write_all(path.as_os_str().as_bytes())?;
+        out.write_all(b"\r\n")?;
+    }
+    Ok(())
+}
+
+// Generate the listing in a worker of the pool writing to a pipe. The worker blocks
+// on a full pipe, so the output held for a slow client is at most the pipe capacity.
+// The receiver tells whether the listing was written without a panic.
+fn stream_listing(
+    pool: &Mutex<ThreadPool>,
+    path: PathBuf,
+    style: ListStyle,
+    writable: bool,
+) -> nix::Result<(File, Receiver<bool>)> {
+    let (reader, writer) = pipe2(OFlag::O_CLOEXEC)?;
+    let mut writer = unsafe { File::from_raw_fd(writer) };
+    let reader = unsafe { File::from_raw_fd(reader) };
+    let (done, generator) = mpsc::channel();
+    pool.lock().unwrap().execute(move || {
+        // A panic mustn't kill the worker of the pool
+        let written = panic::catch_unwind(AssertUnwindSafe(|| {
+            // The reader is closed on an aborted transfer
+            if let Err(e) = write_listing(&path, style, writable, &mut writer) {
+                debug!("Listing of {:?} stopped: {}", path, e);
+            }
+        }));
+        drop(writer);
+        let _ = done.send(written.is_ok());
+    });
+    Ok((reader, generator))
+}
+
+fn send_listing_stream(
+    pool: &Mutex<ThreadPool>,
+    conn: &mut Connection,
+    path: PathBuf,
+    style: ListStyle,
+    writable: bool,
+) -> bool {
+    let (reader, generator) = match stream_listing(pool, path, style, writable) {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Couldn't create the listing pipe: {}", e);
+            return false;
+        }
+    };
+    let sent = conn.splice_from(reader.as_raw_fd()).is_some();
+    drop(reader);
+    if generator.recv() != Ok(true) {
+        warn!("Listing generator panicked");
+        return false;
+    }
+    sent
+}
+
+// An MLSD line, e.g. type=file;size=168;modify=20220328174900;perm=adfrw; lib.rs
+// The type of the cdir and pdir entries is given, they have no size. The perm facts
+// are what the server process may do with the file, the changes only if writable.
+fn add_file_facts(path: &Path, typ: &str, name: &[u8], writable: bool, out: &mut Vec<u8>) {
+    let stat = match stat(path).or_else(|_| lstat(path)) {
+        Ok(stat) => stat,
+        Err(e) => {
+            warn!("Couldn't stat {:?}: {}", path, e);
+            return;
+        }
+    };
+    let dir = is_dir!(stat.st_mode);
+    let typ = match typ {
+        "" if dir => "dir",
+        "" if is_reg!(stat.st_mode) => "file",
+        "" => "OS.unix=special",
+        typ => typ,
+    };
+    let allowed = |mode| access(path, mode).is_ok();
+    let mut perm = String::new();
+    if dir {
+        if allowed(AccessFlags::X_OK) {
+            perm.push('e');
+        }
+        if allowed(AccessFlags::R_OK) {
+            perm.push('l');
+        }
+        if writable && allowed(AccessFlags::W_OK) {
+            perm.push_str("cdfmp");
+        }
+    } else {
+        if allowed(AccessFlags::R_OK) {
+            perm.push('r');
+        }
+        if writable && allowed(AccessFlags::W_OK) {
+            perm.push_str("adfw");
+        }
+    }
+    let mut facts = format!("type={};", typ);
+    if typ == "file" {
+        facts.push_str(&format!("size={};", stat.st_size));
+    }
+    let modify = NaiveDateTime::from_timestamp(stat.st_mtime, 0).format("%Y%m%d%H%M%S");
+    facts.push_str(&format!("modify={};perm={}; ", modify, perm));
+    out.extend(facts.as_bytes());
+    match std::str::from_utf8(name) {
+        Ok(_) => out.extend(name),
+        Err(_) => out.extend(percent_encode(name).as_bytes()),
+    }
+    out.extend(b"\r\n");
+}
+
+// The bytes beyond ASCII as %XX, and % itself so that it can be decoded back
+fn percent_encode(name: &[u8]) -> String {
+    let mut out = String::new();
+    for &byte in name {
+        if byte.is_ascii() && byte != b'%' {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
 // Output directoty information, example:
 // drwxr-xr-x 19 root root 646 Apr  3 12:14 ..
 // drwxr-xr-x  8 root root 272 Mar 29 20:33 handler/
 // -rw-r--r--  1 root root 168 Mar 28 17:49 lib.rs
 // FIXME: fix to user name case
-pub fn add_file_info(path: &str, out: &mut Vec<u8>) {
-    if path.is_empty() {
-        return;
-    }
-    let stat = lstat(path).unwrap();
+pub fn add_file_info(path: &Path, out: &mut Vec<u8>) {
+    // The file may be gone since the directory was read
+    let stat = match lstat(path) {
+        Ok(stat) => stat,
+        Err(e) => {
+            warn!("Couldn't stat {:?}: {}", path, e);
+            return;
+        }
+    };
     let mode = stat.st_mode;
     let file_typ = if is_reg!(mode) {
         "-"
@@ -769,7 +1964,8 @@ pub fn add_file_info(path: &str, out: &mut Vec<u8>) {
     let links = stat.st_nlink;
     let user = User::from_uid(Uid::from_raw(stat.st_uid)).unwrap().unwrap();
     let group = Group::from_gid(Gid::from_raw(stat.st_gid)).unwrap().unwrap();
-    let path = path.split('/').last().unwrap();
+    // An invalid UTF-8 name is marked by the replacement character
+    let path = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy();
 
     let file_str = format!(
         "{file_typ}{rights} {links:3} {owner} {group} {size}  {time} {path}{extra}\r\n",
@@ -800,6 +1996,21 @@ pub fn format_size(st_size: f64) -> String {
     size
 }
 
+// " (12345 bytes, 1.23 MB/s)" for the 226 reply, a transfer takes at least 1 ms
+fn transfer_summary(bytes: u64, elapsed: Duration) -> String {
+    let rate = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+    let (rate, unit) = if rate >= GIGA_BYTE {
+        (rate / GIGA_BYTE, "GB")
+    } else if rate >= MEGA_BYTE {
+        (rate / MEGA_BYTE, "MB")
+    } else if rate >= KILOGYTE {
+        (rate / KILOGYTE, "KB")
+    } else {
+        (rate, "B")
+    };
+    format!(" ({} bytes, {:.2} {}/s)", bytes, rate, unit)
+}
+
 pub fn remove_dir_all(path: &Path) -> bool {
     if !path.is_dir() {
         return false;
@@ -821,3 +2032,1481 @@ pub fn remove_dir_all(path: &Path) -> bool {
     unlink(path).expect(&format!("Couldn't unlink file {}", path.display()));
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::connection::Transport;
+    use crate::net::resolver::NameResolver;
+    use crate::utils::config::LogFlush;
+    use nix::fcntl::{fcntl, FcntlArg};
+    use nix::sys::socket::{send, setsockopt, sockopt, MsgFlags};
+    use nix::sys::time::TimeValLike;
+    use nix::unistd::mkfifo;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::prelude::AsRawFd;
+    use std::os::unix::prelude::{IntoRawFd, RawFd};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Mutex, Once};
+    use std::thread;
+
+    // A logged in session with the client side of its command connection
+    fn new_session(config: &Config, names: Option<NameCache>) -> (Session, TcpStream) {
+        new_session_on("127.0.0.1:0", config, names)
+    }
+    fn new_session_on(
+        addr: &str,
+        config: &Config,
+        names: Option<NameCache>,
+    ) -> (Session, TcpStream) {
+        let listener = TcpListener::bind(addr).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let event_loop = EventLoop::new(Socket(listener.into_raw_fd()));
+        let conn = Connection::new(Socket(server.into_raw_fd()));
+        let mut session = Session::new(config, conn, &event_loop, names);
+        session.welcome = false;
+        (session, client)
+    }
+    fn command(session: &mut Session, client: &mut TcpStream, cmd: &str) -> String {
+        client.write_all(format!("{}\r\n", cmd).as_bytes()).unwrap();
+        session.handle_command();
+        reply(client)
+    }
+    // Read a whole reply, the last line of multi-line reply is "xyz text"
+    fn reply(client: &mut TcpStream) -> String {
+        let mut out = Vec::new();
+        let mut byte = [0u8; 1];
+        loop {
+            client.read_exact(&mut byte).unwrap();
+            out.push(byte[0]);
+            if out.ends_with(b"\r\n") {
+                let line_start = out[..out.len() - 2]
+                    .windows(2)
+                    .rposition(|b| b == b"\r\n")
+                    .map_or(0, |i| i + 2);
+                let line = &out[line_start..];
+                if line.len() > 4 && line[..3].iter().all(u8::is_ascii_digit) && line[3] == b' ' {
+                    break;
+                }
+            }
+        }
+        String::from_utf8_lossy(&out).to_string()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("miniftp_{}_{}", std::process::id(), name))
+    }
+    // Listen a data port for the active mode, return it with the PORT command
+    fn data_listener() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let cmd = format!("PORT 127,0,0,1,{},{}", port >> 8, port & 0xFF);
+        (listener, cmd)
+    }
+    fn set_user(session: &mut Session, name: &str, password: &str) {
+        let mut users = session.config.users.clone();
+        users.insert(name.to_string(), password.to_string());
+        session.set_authenticator(Arc::new(ConfigAuth::new(users, None)));
+    }
+    fn login(session: &mut Session, client: &mut TcpStream) {
+        set_user(session, "tester", "");
+        assert!(command(session, client, "USER tester").starts_with("230"));
+    }
+
+    struct StubResolver;
+    impl NameResolver for StubResolver {
+        fn resolve(&self, _ip: &IpAddr) -> Option<String> {
+            Some("client.example.com".to_string())
+        }
+    }
+    #[test]
+    fn test_peer_name() {
+        let names = NameCache::new(Arc::new(StubResolver));
+        let (mut session, mut client) = new_session(&Config::default(), Some(names.clone()));
+        let port = client.local_addr().unwrap().port();
+        assert_eq!(session.peer_name(), format!("127.0.0.1:{}", port));
+        assert!(command(&mut session, &mut client, "NOOP").starts_with("200"));
+
+        let ip = session.cmd_conn.peer_ip().unwrap();
+        assert!(names.need_resolve(&ip));
+        names.resolve(ip);
+        assert_eq!(session.peer_name(), format!("client.example.com:{}", port));
+    }
+    #[test]
+    fn test_port_privileged() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        let answer = command(&mut session, &mut client, "PORT 127,0,0,1,0,80");
+        assert!(answer.starts_with("501"), "{}", answer);
+        let answer = command(&mut session, &mut client, "PORT 127,0,0,1,117,48");
+        assert!(answer.starts_with("200"), "{}", answer);
+        assert_eq!(session.data_port, Some(30000));
+
+        session.config.reject_privileged_data_ports = false;
+        let answer = command(&mut session, &mut client, "PORT 127,0,0,1,0,80");
+        assert!(answer.starts_with("200"), "{}", answer);
+    }
+
+    // LocalFs counting some calls, and failing others like a restricted storage
+    #[derive(Debug, Default)]
+    struct TestFs {
+        deny_open: bool, // like files the server user can't read
+        read_only: bool, // like a storage mounted read-only
+        reads: AtomicUsize,
+        fsyncs: AtomicUsize, // of the files and of the directories
+        dir_opens: AtomicUsize,
+    }
+    impl FileSystem for TestFs {
+        fn open(&self, path: &Path, oflag: OFlag, mode: Mode) -> nix::Result<RawFd> {
+            if self.deny_open {
+                return Err(Errno::EACCES);
+            }
+            if self.read_only && oflag.intersects(OFlag::O_CREAT | OFlag::O_WRONLY | OFlag::O_RDWR)
+            {
+                return Err(Errno::EROFS);
+            }
+            if path.is_dir() {
+                self.dir_opens.fetch_add(1, Ordering::SeqCst);
+            }
+            LocalFs.open(path, oflag, mode)
+        }
+        fn write(&self, fd: RawFd, buf: &[u8]) -> nix::Result<usize> {
+            if self.read_only {
+                return Err(Errno::EROFS);
+            }
+            LocalFs.write(fd, buf)
+        }
+        fn read(&self, fd: RawFd, buf: &mut [u8]) -> nix::Result<usize> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            LocalFs.read(fd, buf)
+        }
+        fn fsync(&self, fd: RawFd) -> nix::Result<()> {
+            self.fsyncs.fetch_add(1, Ordering::SeqCst);
+            LocalFs.fsync(fd)
+        }
+        fn close(&self, fd: RawFd) -> nix::Result<()> {
+            LocalFs.close(fd)
+        }
+        fn unlink(&self, path: &Path) -> nix::Result<()> {
+            if self.read_only {
+                return Err(Errno::EROFS);
+            }
+            LocalFs.unlink(path)
+        }
+    }
+    #[test]
+    fn test_stor_fsync() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        session.config.fsync_on_close = true;
+        session.config.fsync_dir = true;
+        let counter = Arc::new(TestFs::default());
+        session.fs = counter.clone();
+
+        let (listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let sender = thread::spawn(move || {
+            let (mut data, _) = listener.accept().unwrap();
+            data.write_all(b"durable data").unwrap();
+        });
+        let path = temp_path("fsync");
+        let answer = command(&mut session, &mut client, &format!("STOR {}", path.display()));
+        assert!(answer.starts_with("125"), "{}", answer);
+        assert!(reply(&mut client).starts_with("226"));
+        sender.join().unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"durable data");
+        // fsync the file, then its directory
+        assert_eq!(counter.fsyncs.load(Ordering::SeqCst), 2);
+        assert_eq!(counter.dir_opens.load(Ordering::SeqCst), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_stor_then_mfmt() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+
+        let (listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let sender = thread::spawn(move || {
+            let (mut data, _) = listener.accept().unwrap();
+            data.write_all(b"synced").unwrap();
+        });
+        let path = temp_path("mfmt");
+        let answer = command(&mut session, &mut client, &format!("STOR {}", path.display()));
+        assert!(answer.starts_with("125"), "{}", answer);
+        assert!(reply(&mut client).starts_with("226"));
+        sender.join().unwrap();
+
+        let answer =
+            command(&mut session, &mut client, &format!("MFMT 20200102030405 {}", path.display()));
+        assert!(answer.starts_with("213 Modify=20200102030405;"), "{}", answer);
+        assert_eq!(stat(&path).unwrap().st_mtime, 1577934245);
+        let answer = command(&mut session, &mut client, &format!("MDTM {}", path.display()));
+        assert!(answer.starts_with("213 20200102030405"), "{}", answer);
+
+        // The set form of MDTM is opt-in
+        let set = format!("MDTM 20210102030405 {}", path.display());
+        assert!(command(&mut session, &mut client, &set).starts_with("504"));
+        session.config.mdtm_set = true;
+        assert!(command(&mut session, &mut client, &set).starts_with("213"));
+        assert_eq!(stat(&path).unwrap().st_mtime, 1609556645);
+
+        // Far beyond the range of the nanoseconds in an i64
+        let far = format!("MFMT 99991231235959.5 {}", path.display());
+        assert!(command(&mut session, &mut client, &far).starts_with("213"));
+        // The filesystem may clamp it, e.g. ext4 stops in 2446
+        assert!(stat(&path).unwrap().st_mtime > i64::MAX / 1_000_000_000);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_retr_ascii_binary_guard() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        session.config.ascii_binary_guard = BinaryGuard::Refuse;
+        let path = temp_path("ascii_binary");
+        std::fs::write(&path, b"\x7fELF\x02\x01\x01\x00\x00").unwrap();
+        let retr = format!("RETR {}", path.display());
+
+        assert!(command(&mut session, &mut client, "TYPE A").starts_with("200"));
+        let (_listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let answer = command(&mut session, &mut client, &retr);
+        assert_eq!(answer, "550 File is binary, use TYPE I\r\n");
+
+        // The binary type is the way to get it
+        assert!(command(&mut session, &mut client, "TYPE I").starts_with("200"));
+        let (listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let receiver = thread::spawn(move || {
+            let (mut data, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            data.read_to_end(&mut buf).unwrap();
+            buf
+        });
+        assert!(command(&mut session, &mut client, &retr).starts_with("150"));
+        assert!(reply(&mut client).starts_with("226"));
+        assert_eq!(receiver.join().unwrap(), std::fs::read(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_retr_data_conn_closed() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        let path = temp_path("retr_closed");
+        std::fs::write(&path, vec![b'x'; 16 * 1024 * 1024]).unwrap();
+
+        let (listener, port) = data_listener();
+        // A small receive window, so that the file can't be buffered by the kernel
+        setsockopt(listener.as_raw_fd(), sockopt::RcvBuf, &4096).unwrap();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        client.write_all(format!("RETR {}\r\n", path.display()).as_bytes()).unwrap();
+        let handle = thread::spawn(move || {
+            session.attach_to_current_thread();
+            session.handle_command();
+            session
+        });
+        let (mut data, _) = listener.accept().unwrap();
+        let mut buf = vec![0u8; 4096];
+        data.read_exact(&mut buf).unwrap();
+        drop(data);
+
+        assert!(reply(&mut client).starts_with("150"));
+        let answer = reply(&mut client);
+        assert!(answer.starts_with("426"), "{}", answer);
+        handle.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_max_arg_len() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.config.max_arg_len = 16;
+        let answer = command(&mut session, &mut client, &format!("CWD /{}", "a".repeat(16)));
+        assert!(answer.starts_with("501"), "{}", answer);
+        let answer = command(&mut session, &mut client, "CWD /");
+        assert!(answer.starts_with("250"), "{}", answer);
+    }
+    #[test]
+    fn test_retr_transfer_state() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        let path = temp_path("retr_state");
+        std::fs::write(&path, vec![b'x'; 16 * 1024 * 1024]).unwrap();
+
+        let (listener, port) = data_listener();
+        setsockopt(listener.as_raw_fd(), sockopt::RcvBuf, &4096).unwrap();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let tracker = session.transfer.clone();
+        assert_eq!(tracker.get(), TransferState::Idle);
+        client.write_all(format!("RETR {}\r\n", path.display()).as_bytes()).unwrap();
+        let handle = thread::spawn(move || {
+            session.attach_to_current_thread();
+            session.handle_command();
+        });
+        let (mut data, _) = listener.accept().unwrap();
+        assert!(reply(&mut client).starts_with("150"));
+        let mut buf = vec![0u8; 4096];
+        data.read_exact(&mut buf).unwrap();
+        // The file is far larger than the socket buffers
+        assert_eq!(tracker.get(), TransferState::Sending);
+
+        let mut received = buf.len();
+        received += data.read_to_end(&mut buf).unwrap();
+        assert_eq!(received, 16 * 1024 * 1024);
+        assert!(reply(&mut client).starts_with("226"));
+        assert_eq!(tracker.get(), TransferState::Done);
+        handle.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_retr_permission_denied() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        session.fs = Arc::new(TestFs { deny_open: true, ..Default::default() });
+        let path = temp_path("retr_denied");
+        std::fs::write(&path, b"secret").unwrap();
+        let (listener, port) = data_listener();
+        listener.set_nonblocking(true).unwrap();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+
+        let answer = command(&mut session, &mut client, &format!("RETR {}", path.display()));
+        assert!(answer.starts_with("550 Permission denied"), "{}", answer);
+        // No data connection was opened for it
+        let err = listener.accept().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_retr_control_closed() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        let path = temp_path("retr_control_closed");
+        let size = 32 * 1024 * 1024;
+        std::fs::write(&path, vec![7u8; size]).unwrap();
+        let port = pasv(&mut session, &mut client);
+        let mut data = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+        client.write_all(format!("RETR {}\r\n", path.display()).as_bytes()).unwrap();
+        let receiver = thread::spawn(move || {
+            let mut buf = vec![0u8; 256 * 1024];
+            data.read_exact(&mut buf).unwrap();
+            // The client vanishes in the middle of the transfer
+            drop(client);
+            thread::sleep(Duration::from_millis(100));
+            let mut rest = Vec::new();
+            data.read_to_end(&mut rest).unwrap();
+            buf.len() + rest.len()
+        });
+        session.handle_command();
+        assert!(receiver.join().unwrap() < size);
+        assert!(!session.cmd_conn.connected());
+        assert!(!session.data.is_listening() && !session.data.is_held());
+        assert_eq!(session.transfer_state(), TransferState::Done);
+        assert!(session.transfer.progress().0 < size as u64);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_retr_userspace() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        let fs = Arc::new(TestFs::default());
+        session.fs = fs.clone();
+        let path = temp_path("retr_userspace");
+        let content: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        for userspace in [false, true] {
+            session.config.force_userspace_transfer = userspace;
+            fs.reads.store(0, Ordering::SeqCst);
+            let (listener, port) = data_listener();
+            assert!(command(&mut session, &mut client, &port).starts_with("200"));
+            let receiver = thread::spawn(move || {
+                let (mut data, _) = listener.accept().unwrap();
+                let mut out = Vec::new();
+                data.read_to_end(&mut out).unwrap();
+                out
+            });
+            let answer = command(&mut session, &mut client, &format!("RETR {}", path.display()));
+            assert!(answer.starts_with("150"), "{}", answer);
+            assert!(reply(&mut client).starts_with("226"));
+            assert_eq!(receiver.join().unwrap(), content);
+            assert_eq!(
+                session.transfer.progress(),
+                (content.len() as u64, Some(content.len() as u64))
+            );
+            // sendfile doesn't read the file through the storage backend
+            assert_eq!(fs.reads.load(Ordering::SeqCst) > 0, userspace);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_port_nat_ip() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        let answer = command(&mut session, &mut client, "PORT 192,168,1,20,117,48");
+        assert!(answer.starts_with("501"), "{}", answer);
+        assert_eq!(session.data_addr, None);
+
+        session.config.ignore_port_ip = true;
+        let (listener, _) = data_listener();
+        let port = listener.local_addr().unwrap().port();
+        let cmd = format!("PORT 192,168,1,20,{},{}", port >> 8, port & 0xFF);
+        assert!(command(&mut session, &mut client, &cmd).starts_with("200"));
+        let peer = session.cmd_conn.peer_ip().unwrap();
+        assert_eq!(session.data_addr, Some(SocketAddr::new(peer, port)));
+
+        // The data connection goes to the control peer
+        assert!(command(&mut session, &mut client, "NLST /").starts_with("150"));
+        let (mut data, addr) = listener.accept().unwrap();
+        assert_eq!(addr.ip(), peer);
+        let mut out = Vec::new();
+        data.read_to_end(&mut out).unwrap();
+        assert!(reply(&mut client).starts_with("226"));
+    }
+    #[test]
+    fn test_stat_transfer() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        let path = temp_path("stat");
+        std::fs::write(&path, vec![b'x'; 16 * 1024 * 1024]).unwrap();
+
+        let (listener, port) = data_listener();
+        setsockopt(listener.as_raw_fd(), sockopt::RcvBuf, &4096).unwrap();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let answer = command(&mut session, &mut client, "STAT");
+        assert!(answer.starts_with("211-"), "{}", answer);
+        assert!(answer.contains("Data connection: active"), "{}", answer);
+        assert!(answer.contains("No data transfer in progress"), "{}", answer);
+
+        client.write_all(format!("RETR {}\r\n", path.display()).as_bytes()).unwrap();
+        let handle = thread::spawn(move || {
+            session.attach_to_current_thread();
+            session.handle_command();
+        });
+        let (mut data, _) = listener.accept().unwrap();
+        assert!(reply(&mut client).starts_with("150"));
+        // Sent before draining the data, so it's seen in the middle of the transfer
+        client.write_all(b"STAT\r\n").unwrap();
+        let reader = thread::spawn(move || {
+            let mut out = Vec::new();
+            data.read_to_end(&mut out).unwrap();
+            out.len()
+        });
+        let answer = reply(&mut client);
+        assert!(answer.starts_with("211-"), "{}", answer);
+        assert!(answer.contains("Transfer: Sending"), "{}", answer);
+        assert!(answer.contains("of 16777216 bytes"), "{}", answer);
+        assert!(reply(&mut client).starts_with("226"));
+        assert_eq!(reader.join().unwrap(), 16 * 1024 * 1024);
+        handle.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_auth() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        let answer = command(&mut session, &mut client, "AUTH TLS");
+        assert!(answer.starts_with("431"), "{}", answer);
+        assert!(command(&mut session, &mut client, "AUTH KERBEROS").starts_with("504"));
+    }
+    #[test]
+    fn test_reply_unknown_unimplemented() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        let answer = command(&mut session, &mut client, "FOOBAR x");
+        assert!(answer.starts_with("500"), "{}", answer);
+        let answer = command(&mut session, &mut client, "STOU");
+        assert!(answer.starts_with("502"), "{}", answer);
+        session.config.disabled_commands = vec!["size".to_string()];
+        let answer = command(&mut session, &mut client, "SIZE /etc/passwd");
+        assert!(answer.starts_with("502"), "{}", answer);
+
+        let answer = command(&mut session, &mut client, "CWD");
+        assert!(answer.starts_with("501"), "{}", answer);
+        let answer = command(&mut session, &mut client, "PORT 127,0,0,1");
+        assert!(answer.starts_with("501"), "{}", answer);
+        let answer = command(&mut session, &mut client, "TYPE X");
+        assert!(answer.starts_with("504"), "{}", answer);
+        assert!(command(&mut session, &mut client, "NOOP").starts_with("200"));
+    }
+    #[test]
+    fn test_rein() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        assert!(command(&mut session, &mut client, "TYPE A").starts_with("200"));
+        let answer = command(&mut session, &mut client, "REIN");
+        assert!(answer.starts_with("220"), "{}", answer);
+        assert!(!session.is_logged());
+        assert_eq!(session.transfer_type, TransferType::BINARY);
+        assert_eq!(session.cur_dir, session.server_root);
+        assert!(command(&mut session, &mut client, "USER tester").starts_with("230"));
+
+        // The USER in the same write as the REIN is for the new login
+        let answer = command(&mut session, &mut client, "REIN\r\nUSER tester");
+        assert!(answer.starts_with("220"), "{}", answer);
+        assert!(reply(&mut client).starts_with("230"));
+        assert!(session.is_logged());
+    }
+    #[test]
+    fn test_stor_ascii() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        assert!(command(&mut session, &mut client, "TYPE A").starts_with("200"));
+
+        let (listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let sender = thread::spawn(move || {
+            let (mut data, _) = listener.accept().unwrap();
+            // The CRLF is split between two writes
+            data.write_all(b"line 1\r\nline 2\r").unwrap();
+            thread::sleep(std::time::Duration::from_millis(50));
+            data.write_all(b"\nline 3\r\n").unwrap();
+        });
+        let path = temp_path("ascii");
+        let answer = command(&mut session, &mut client, &format!("STOR {}", path.display()));
+        assert!(answer.starts_with("125"), "{}", answer);
+        assert!(reply(&mut client).starts_with("226"));
+        sender.join().unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"line 1\nline 2\nline 3\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_site_reloaduser() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        let path = temp_path("reloaduser.yaml");
+        std::fs::write(&path, "users:\n  tester: old\n").unwrap();
+        let users = HashMap::from([("tester".to_string(), "old".to_string())]);
+        session.set_authenticator(Arc::new(ConfigAuth::new(users, Some(path.clone()))));
+        assert!(command(&mut session, &mut client, "USER tester").starts_with("331"));
+        assert!(command(&mut session, &mut client, "PASS old").starts_with("230"));
+
+        std::fs::write(&path, "users:\n  tester: new\n").unwrap();
+        assert!(command(&mut session, &mut client, "SITE RELOADUSER tester").starts_with("550"));
+        session.is_admin = true;
+        let answer = command(&mut session, &mut client, "SITE RELOADUSER tester");
+        assert!(answer.starts_with("200 User tester reloaded"), "{}", answer);
+
+        assert!(command(&mut session, &mut client, "USER tester").starts_with("331"));
+        assert!(command(&mut session, &mut client, "PASS old").starts_with("530"));
+        assert!(command(&mut session, &mut client, "USER tester").starts_with("331"));
+        assert!(command(&mut session, &mut client, "PASS new").starts_with("230"));
+
+        // The backend can't be read
+        std::fs::remove_file(&path).unwrap();
+        session.is_admin = true;
+        let answer = command(&mut session, &mut client, "SITE RELOADUSER tester");
+        assert!(answer.starts_with("450"), "{}", answer);
+    }
+    #[test]
+    fn test_max_logins_per_user() {
+        let mut config = Config::default();
+        config.max_logins_per_user = 2;
+        let logins = LoginCounter::new();
+        let mut sessions = Vec::new();
+        for _ in 0..3 {
+            let (mut session, client) = new_session(&config, None);
+            set_user(&mut session, "tester", "secret");
+            session.set_login_counter(logins.clone());
+            sessions.push((session, client));
+        }
+        for (session, client) in sessions.iter_mut().take(2) {
+            assert!(command(session, client, "USER tester").starts_with("331"));
+            assert!(command(session, client, "PASS secret").starts_with("230"));
+        }
+        assert_eq!(logins.count("tester"), 2);
+        let (session, client) = &mut sessions[2];
+        assert!(command(session, client, "USER tester").starts_with("331"));
+        // The socket is full when the 530 is sent, it's still out before the close
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let transport = ScriptedTransport { script: vec![None], written: written.clone() };
+        session.cmd_conn.set_transport(Box::new(transport));
+        client.write_all(b"PASS secret\r\n").unwrap();
+        session.handle_command();
+        let answer = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+        assert_eq!(answer, "530 Too many connections for this account\r\n");
+        assert!(!session.is_logged());
+        assert!(!session.cmd_conn.connected());
+        assert_eq!(logins.count("tester"), 2);
+
+        // A logout, or a dropped session, frees a login
+        let (session, client) = &mut sessions[0];
+        assert!(command(session, client, "QUIT").starts_with("221"));
+        assert_eq!(logins.count("tester"), 1);
+        sessions.remove(1);
+        assert_eq!(logins.count("tester"), 0);
+        let (mut session, mut client) = new_session(&config, None);
+        set_user(&mut session, "tester", "");
+        session.set_login_counter(logins.clone());
+        assert!(command(&mut session, &mut client, "USER tester").starts_with("230"));
+        assert_eq!(logins.count("tester"), 1);
+    }
+    #[test]
+    fn test_site_selftest() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        let root = temp_path("selftest");
+        std::fs::create_dir_all(&root).unwrap();
+        session.server_root = root.clone();
+
+        session.is_admin = false;
+        assert!(command(&mut session, &mut client, "SITE SELFTEST").starts_with("550"));
+        session.is_admin = true;
+        let answer = command(&mut session, &mut client, "SITE SELFTEST");
+        assert!(answer.starts_with("200 Storage OK"), "{}", answer);
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+
+        session.fs = Arc::new(TestFs { read_only: true, ..Default::default() });
+        let answer = command(&mut session, &mut client, "SITE selftest");
+        assert!(answer.starts_with("450"), "{}", answer);
+        assert!(answer.contains("create"), "{}", answer);
+        std::fs::remove_dir(&root).unwrap();
+    }
+    #[test]
+    fn test_site_replies() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        let answer = command(&mut session, &mut client, "SITE UMASK 18");
+        assert!(answer.starts_with("200 UMASK set to 18"), "{}", answer);
+        let answer = command(&mut session, &mut client, "SITE UMASK rw");
+        assert!(answer.starts_with("501"), "{}", answer);
+        assert_eq!(session.mode, 18);
+        let answer = command(&mut session, &mut client, "SITE CHMOD rw-r--r-- a");
+        assert!(answer.starts_with("501"), "{}", answer);
+        assert!(command(&mut session, &mut client, "SITE CHMOD 420").starts_with("501"));
+        let missing = format!("SITE CHMOD 420 {}", temp_path("site_chmod").display());
+        let answer = command(&mut session, &mut client, &missing);
+        assert!(answer.starts_with("550"), "{}", answer);
+
+        let answer = command(&mut session, &mut client, "SITE FOO");
+        assert!(answer.starts_with("502"), "{}", answer);
+        assert!(command(&mut session, &mut client, "SITE").starts_with("502"));
+    }
+    #[test]
+    fn test_pasv_listener_dropped() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        session.data_port = Some(port - 2);
+        let answer = command(&mut session, &mut client, "PASV");
+        assert!(answer.starts_with("227"), "{}", answer);
+        let address = format!("(127,0,0,1,{},{})", port >> 8, port & 0xFF);
+        assert!(answer.contains(&address), "{}", answer);
+        assert!(session.data.is_listening());
+        let fd = session.data.listen_fd().unwrap();
+        assert!(!session.event_loop.is_registered(fd));
+
+        // The client is gone before connecting the data port
+        drop(client);
+        drop(session);
+        let err = TcpStream::connect(("127.0.0.1", port)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+    }
+    #[test]
+    fn test_pipelined_burst() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.config.max_commands_per_read = 8;
+        session.config.max_pending_commands = 32;
+        // Like the accepted connections, the backlog is drained without blocking
+        let fd = session.cmd_conn.get_fd().as_raw_fd();
+        fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+        client.write_all("NOOP\r\n".repeat(100).as_bytes()).unwrap();
+        thread::sleep(std::time::Duration::from_millis(50));
+
+        session.handle_command();
+        for _ in 0..8 {
+            assert!(reply(&mut client).starts_with("200"));
+        }
+        assert_eq!(session.backlog(), 92);
+        client.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 16];
+        assert!(client.read(&mut buf).is_err());
+        client.set_nonblocking(false).unwrap();
+
+        // The backlog is over the cap, more commands stay in the socket
+        client.write_all("NOOP\r\n".repeat(10).as_bytes()).unwrap();
+        thread::sleep(std::time::Duration::from_millis(50));
+        session.handle_command();
+        assert_eq!(session.backlog(), 84);
+
+        while session.backlog() > 0 {
+            session.handle_command();
+        }
+        for _ in 8..110 {
+            assert!(reply(&mut client).starts_with("200"));
+        }
+        assert_eq!(session.backlog(), 0);
+    }
+    #[test]
+    fn test_stor_special_file() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        let path = temp_path("stor_fifo");
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        let (listener, port_cmd) = data_listener();
+        assert!(command(&mut session, &mut client, &port_cmd).starts_with("200"));
+        let answer = command(&mut session, &mut client, &format!("STOR {}", path.display()));
+        assert!(answer.starts_with("550"), "{}", answer);
+        assert!(listener.accept().is_ok());
+        // Neither blocked on the FIFO nor replaced it
+        let st = stat(&path).unwrap();
+        assert_eq!(SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT, SFlag::S_IFIFO);
+        std::fs::remove_file(&path).unwrap();
+
+        let (_listener, port_cmd) = data_listener();
+        assert!(command(&mut session, &mut client, &port_cmd).starts_with("200"));
+        let answer = command(&mut session, &mut client, "STOR /dev/null");
+        assert!(answer.starts_with("550 /dev/null is not a regular file"), "{}", answer);
+    }
+    #[test]
+    fn test_stor_abort() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        session.config.delete_partial_upload = true;
+
+        let (listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let mut control = client.try_clone().unwrap();
+        let sender = thread::spawn(move || {
+            let (mut data, _) = listener.accept().unwrap();
+            data.write_all(b"partial data").unwrap();
+            thread::sleep(std::time::Duration::from_millis(50));
+            control.write_all(b"STAT\r\nABOR\r\n").unwrap();
+            // The upload is stalled rather than finished
+            thread::sleep(std::time::Duration::from_millis(500));
+        });
+        let path = temp_path("abort");
+        let answer = command(&mut session, &mut client, &format!("STOR {}", path.display()));
+        assert!(answer.starts_with("125"), "{}", answer);
+        let answer = reply(&mut client);
+        assert!(answer.starts_with("211"), "{}", answer);
+        assert!(answer.contains("Transfer: Receiving 12 bytes"), "{}", answer);
+        assert!(reply(&mut client).starts_with("426"));
+        assert!(reply(&mut client).starts_with("226"));
+        assert_eq!(session.transfer_state(), TransferState::Done);
+        assert!(!path.exists());
+        sender.join().unwrap();
+    }
+    #[test]
+    fn test_stor_pipelined() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+
+        let (listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let mut control = client.try_clone().unwrap();
+        let sender = thread::spawn(move || {
+            let (mut data, _) = listener.accept().unwrap();
+            data.write_all(b"first ").unwrap();
+            thread::sleep(std::time::Duration::from_millis(50));
+            control.write_all(b"STAT\r\nPWD\r\nSTAT\r\n").unwrap();
+            thread::sleep(std::time::Duration::from_millis(50));
+            data.write_all(b"second").unwrap();
+        });
+        let path = temp_path("pipelined");
+        let answer = command(&mut session, &mut client, &format!("STOR {}", path.display()));
+        assert!(answer.starts_with("125"), "{}", answer);
+        // The first STAT is answered during the transfer, PWD and the STAT behind it after
+        let answer = reply(&mut client);
+        assert!(answer.contains("Transfer: Receiving 6 bytes"), "{}", answer);
+        assert!(reply(&mut client).starts_with("226"));
+        assert!(reply(&mut client).starts_with("257"));
+        let answer = reply(&mut client);
+        assert!(answer.contains("No data transfer in progress"), "{}", answer);
+        assert_eq!(std::fs::read(&path).unwrap(), b"first second");
+        sender.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_stor_stalled() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        session.config.data_connect_timeout = 1;
+
+        let (listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let sender = thread::spawn(move || {
+            let (mut data, _) = listener.accept().unwrap();
+            data.write_all(b"partial data").unwrap();
+            // Neither more data nor the end of it
+            thread::sleep(std::time::Duration::from_millis(1500));
+        });
+        let path = temp_path("stalled");
+        let answer = command(&mut session, &mut client, &format!("STOR {}", path.display()));
+        assert!(answer.starts_with("125"), "{}", answer);
+        let answer = reply(&mut client);
+        assert!(answer.starts_with("426 Data connection timed out"), "{}", answer);
+        assert_eq!(std::fs::read(&path).unwrap(), b"partial data");
+        sender.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_stor_synch_abort() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+
+        let (listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let mut control = client.try_clone().unwrap();
+        let sender = thread::spawn(move || {
+            let (mut data, _) = listener.accept().unwrap();
+            data.write_all(b"partial data").unwrap();
+            thread::sleep(std::time::Duration::from_millis(50));
+            // IAC IP in-band, then IAC DM with the DM as the urgent byte, then ABOR
+            control.write_all(b"\xff\xf4\xff").unwrap();
+            thread::sleep(std::time::Duration::from_millis(50));
+            send(control.as_raw_fd(), b"\xf2", MsgFlags::MSG_OOB).unwrap();
+            thread::sleep(std::time::Duration::from_millis(50));
+            control.write_all(b"ABOR\r\n").unwrap();
+            thread::sleep(std::time::Duration::from_millis(500));
+        });
+        let path = temp_path("synch_abort");
+        let answer = command(&mut session, &mut client, &format!("STOR {}", path.display()));
+        assert!(answer.starts_with("125"), "{}", answer);
+        let answer = reply(&mut client);
+        assert!(answer.starts_with("426"), "{}", answer);
+        assert!(reply(&mut client).starts_with("226 ABOR successful"));
+        assert!(!session.cmd_conn.poll_urgent());
+        sender.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_max_mutations_per_sec() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        session.config.max_mutations_per_sec = 5;
+        let paths = (0..8).map(|i| temp_path(&format!("mutation_{}", i))).collect::<Vec<_>>();
+        for path in paths.iter() {
+            std::fs::write(path, b"x").unwrap();
+        }
+        let answers = paths
+            .iter()
+            .map(|path| command(&mut session, &mut client, &format!("DELE {}", path.display())))
+            .collect::<Vec<_>>();
+        // A burst of a second of them, then the throttling
+        assert!(answers[..5].iter().all(|answer| answer.starts_with("250")), "{:?}", answers);
+        assert!(answers[5..].iter().any(|answer| answer.starts_with("450 Too many operations")));
+
+        thread::sleep(Duration::from_millis(400));
+        let remaining = paths.iter().filter(|path| path.exists()).collect::<Vec<_>>();
+        let answer =
+            command(&mut session, &mut client, &format!("DELE {}", remaining[0].display()));
+        assert!(answer.starts_with("250"), "{}", answer);
+        for path in paths.iter().filter(|path| path.exists()) {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+    // Takes the given number of bytes per write, None for EAGAIN, all of them once
+    // the script is over
+    #[derive(Debug)]
+    struct ScriptedTransport {
+        script: Vec<Option<usize>>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+    impl Transport for ScriptedTransport {
+        fn write(&mut self, buf: &[u8]) -> nix::Result<usize> {
+            let n = match self.script.first().copied() {
+                Some(step) => {
+                    self.script.remove(0);
+                    step.ok_or(Errno::EAGAIN)?.min(buf.len())
+                }
+                None => buf.len(),
+            };
+            self.written.lock().unwrap().extend(&buf[..n]);
+            Ok(n)
+        }
+    }
+    #[test]
+    fn test_multi_line_reply_eagain() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let script = vec![Some(10), None, None];
+        let transport = ScriptedTransport { script, written: written.clone() };
+        session.cmd_conn.set_transport(Box::new(transport));
+        // Nonblocking like the accepted connections, the EPOLLOUT wakeup reads nothing
+        let fd = session.cmd_conn.get_fd().0;
+        fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+
+        client.write_all(b"STAT\r\nNOOP\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        session.handle_command();
+        // The socket got full in the middle of the STAT reply, nothing of NOOP is out
+        assert_eq!(written.lock().unwrap().len(), 10);
+        assert!(session.cmd_conn.has_pending_output());
+
+        session.set_revents(&EpollFlags::EPOLLOUT);
+        session.handle_command();
+        assert!(!session.cmd_conn.has_pending_output());
+        let out = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+        let mut stat = Vec::new();
+        let answer = Answer::new(ReplyCode::SystemStatus, &session.status_message());
+        FtpCodec.encode(answer, &mut stat).unwrap();
+        let stat = String::from_utf8(stat).unwrap();
+        assert!(stat.lines().count() > 2, "{}", stat);
+        // The whole STAT block, then the NOOP reply
+        let (first, second) = out.split_at(stat.len());
+        assert_eq!(first, stat);
+        assert!(second.starts_with("200 ") && second.ends_with("\r\n"), "{}", second);
+        assert_eq!(second.matches("\r\n").count(), 1);
+    }
+    #[test]
+    fn test_rein_quit_keep_output() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let script = vec![Some(10), None, None];
+        let transport = ScriptedTransport { script, written: written.clone() };
+        session.cmd_conn.set_transport(Box::new(transport));
+        let fd = session.cmd_conn.get_fd().0;
+        fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+
+        // The socket is full, the REIN doesn't drop what's queued before its reply
+        client.write_all(b"STAT\r\nREIN\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        session.handle_command();
+        assert!(session.cmd_conn.has_pending_output());
+
+        // The QUIT flushes it all before the close
+        client.write_all(b"QUIT\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        session.handle_command();
+        assert!(!session.cmd_conn.has_pending_output());
+        assert!(!session.cmd_conn.connected());
+        let out = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+        assert!(out.starts_with("211-"), "{}", out);
+        assert!(out.contains("\r\n220 "), "{}", out);
+        assert!(out.ends_with("221 Goodbye\r\n"), "{}", out);
+    }
+    #[test]
+    fn test_wrong_thread() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        client.write_all(b"NOOP\r\n").unwrap();
+        let (session, result) = thread::spawn(move || {
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| session.handle_command()));
+            (session, result)
+        })
+        .join()
+        .unwrap();
+        let err = result.unwrap_err();
+        let message = err.downcast_ref::<String>().unwrap();
+        assert!(message.contains("is owned by thread"), "{}", message);
+
+        // Handed over to the worker, as the event loop does
+        let mut session = thread::spawn(move || {
+            let mut session = session;
+            session.attach_to_current_thread();
+            session.handle_command();
+            session
+        })
+        .join()
+        .unwrap();
+        assert!(reply(&mut client).starts_with("200"));
+        session.attach_to_current_thread();
+        assert!(command(&mut session, &mut client, "NOOP").starts_with("200"));
+    }
+    #[test]
+    fn test_pasv_ipv6() {
+        let (mut session, mut client) = new_session_on("[::1]:0", &Config::default(), None);
+        login(&mut session, &mut client);
+        let answer = command(&mut session, &mut client, "PASV");
+        assert!(answer.starts_with("425"), "{}", answer);
+        assert!(answer.contains("EPSV"), "{}", answer);
+        assert!(!session.data.is_listening());
+    }
+    #[test]
+    fn test_list_non_utf8() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        assert!(command(&mut session, &mut client, "OPTS UTF8 ON").starts_with("200"));
+
+        let dir = temp_path("non_utf8");
+        std::fs::create_dir_all(&dir).unwrap();
+        let name = OsStr::from_bytes(b"bad\xff.txt");
+        std::fs::write(dir.join(name), b"data").unwrap();
+        std::fs::write(dir.join("good.txt"), b"data").unwrap();
+
+        let out = session.list_output(&dir, ListStyle::Names);
+        assert_eq!(out, b"bad\xff.txt\r\ngood.txt\r\n".to_vec());
+        let out = String::from_utf8(session.list_output(&dir, ListStyle::Long)).unwrap();
+        assert!(out.contains("bad\u{fffd}.txt\r\n"), "{}", out);
+        assert!(out.contains("good.txt\r\n"), "{}", out);
+        let out = String::from_utf8(session.list_output(&dir, ListStyle::Facts)).unwrap();
+        assert!(out.contains("; bad%FF.txt\r\n"), "{}", out);
+        assert!(out.contains("; good.txt\r\n"), "{}", out);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_mlsd_cdir_facts() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        let dir = temp_path("mlsd");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let file = dir.join("a.txt");
+        std::fs::write(&file, b"data").unwrap();
+        let time = NaiveDateTime::parse_from_str("20200102030405", "%Y%m%d%H%M%S").unwrap();
+        let mtime = TimeSpec::seconds(time.timestamp());
+        utimensat(None, &dir, &mtime, &mtime, UtimensatFlags::FollowSymlink).unwrap();
+
+        let (listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let receiver = thread::spawn(move || {
+            let (mut data, _) = listener.accept().unwrap();
+            let mut out = String::new();
+            data.read_to_string(&mut out).unwrap();
+            out
+        });
+        // A file is refused before the data connection is opened
+        let answer = command(&mut session, &mut client, &format!("MLSD {}", file.display()));
+        assert!(answer.starts_with("501"), "{}", answer);
+        let answer = command(&mut session, &mut client, &format!("MLSD {}", dir.display()));
+        assert!(answer.starts_with("150"), "{}", answer);
+        assert!(reply(&mut client).starts_with("226"));
+        let out = receiver.join().unwrap();
+        let lines = out.split_terminator("\r\n").collect::<Vec<_>>();
+        // The test process owns the files, an admin gets every perm
+        assert_eq!(lines[0], "type=cdir;modify=20200102030405;perm=elcdfmp; .");
+        assert!(lines[1].starts_with("type=pdir;modify="), "{}", lines[1]);
+        assert!(lines[1].ends_with("; .."), "{}", lines[1]);
+        assert!(lines.iter().any(|line| line.starts_with("type=file;size=4;modify=")
+            && line.ends_with(";perm=radfw; a.txt")));
+        assert!(lines.iter().any(|line| line.starts_with("type=dir;") && line.ends_with(" sub")));
+        assert_eq!(lines.len(), 4);
+
+        // Another user may only read, whatever the server process may do
+        session.is_admin = false;
+        let out = String::from_utf8(session.list_output(&dir, ListStyle::Facts)).unwrap();
+        assert!(out.starts_with("type=cdir;modify=20200102030405;perm=el; .\r\n"), "{}", out);
+        assert!(out.contains(";perm=r; a.txt\r\n"), "{}", out);
+
+        let (_listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let file = dir.join("a.txt");
+        let answer = command(&mut session, &mut client, &format!("MLSD {}", file.display()));
+        assert!(answer.starts_with("501"), "{}", answer);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_stream_listing() {
+        let dir = temp_path("stream_listing");
+        std::fs::create_dir_all(&dir).unwrap();
+        for i in 0..3000 {
+            std::fs::write(dir.join(format!("file_{:04}_{}", i, "x".repeat(40))), b"").unwrap();
+        }
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        let expected = session.list_output(&dir, ListStyle::Long);
+        assert!(expected.len() > 4 * 64 * 1024);
+
+        // The generator waits for a slow reader instead of buffering the listing
+        let pool = Arc::new(Mutex::new(ThreadPool::new(2)));
+        let (mut reader, generator) =
+            stream_listing(&pool, dir.clone(), ListStyle::Long, true).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(generator.try_recv().is_err());
+        let mut out: Vec<u8> = Vec::new();
+        let mut chunk = [0u8; 16 * 1024];
+        loop {
+            match reader.read(&mut chunk).unwrap() {
+                0 => break,
+                n => out.extend(&chunk[..n]),
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(generator.recv(), Ok(true));
+        assert_eq!(out, expected);
+
+        session.config.stream_listing = true;
+        session.set_listing_pool(pool);
+        let port = pasv(&mut session, &mut client);
+        let mut data = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let receiver = thread::spawn(move || {
+            let mut out = Vec::new();
+            data.read_to_end(&mut out).unwrap();
+            out
+        });
+        let answer = command(&mut session, &mut client, &format!("LIST {}", dir.display()));
+        assert!(answer.starts_with("150"), "{}", answer);
+        assert!(reply(&mut client).starts_with("226"));
+        assert_eq!(receiver.join().unwrap(), expected);
+        assert_eq!(session.transfer.progress().0, expected.len() as u64);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    // Keeps the log lines, for the tests checking them
+    struct CaptureLogger;
+    lazy_static! {
+        static ref LOG_LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    }
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record) {
+            LOG_LINES.lock().unwrap().push(record.args().to_string());
+        }
+        fn flush(&self) {}
+    }
+    fn capture_logs() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Debug);
+        });
+    }
+    #[test]
+    fn test_data_conn_log_id() {
+        capture_logs();
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        let id = session.cmd_conn.id().to_string();
+        let dir = temp_path("log_id");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file"), b"data").unwrap();
+
+        let port = pasv(&mut session, &mut client);
+        let mut data = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let answer = command(&mut session, &mut client, &format!("NLST {}", dir.display()));
+        assert!(answer.starts_with("150"), "{}", answer);
+        assert!(reply(&mut client).starts_with("226"));
+        let mut out = Vec::new();
+        data.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"file\r\n");
+
+        let tag = format!("[{}.data] ", id);
+        let lines = LOG_LINES.lock().unwrap();
+        let tagged: Vec<_> = lines.iter().filter(|line| line.starts_with(&tag)).collect();
+        assert!(tagged.iter().any(|line| line.contains("Data connection")), "{:?}", tagged);
+        assert!(tagged.iter().any(|line| line.contains("Transfer state")), "{:?}", tagged);
+        drop(lines);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_opts() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        assert_eq!(command(&mut session, &mut client, "OPTS UTF8"), "200 Always in UTF8 mode\r\n");
+        assert!(command(&mut session, &mut client, "opts utf8 on").starts_with("200"));
+        let answer = command(&mut session, &mut client, "OPTS UTF8 OFF");
+        assert_eq!(answer, "501 Option not understood\r\n");
+        let answer = command(&mut session, &mut client, "OPTS MODE Z LEVEL 9");
+        assert_eq!(answer, "501 Option not understood\r\n");
+        let answer = command(&mut session, &mut client, "OPTS");
+        assert!(answer.starts_with("501"), "{}", answer);
+    }
+    #[test]
+    fn test_max_failed_commands() {
+        let mut config = Config::default();
+        config.max_failed_commands = 3;
+        let (mut session, mut client) = new_session(&config, None);
+        // A success in between resets the count
+        for _ in 0..3 {
+            assert!(command(&mut session, &mut client, "XYZ").starts_with("500"));
+        }
+        assert!(command(&mut session, &mut client, "NOOP").starts_with("200"));
+        for _ in 0..3 {
+            assert!(command(&mut session, &mut client, "PASS").starts_with("501"));
+        }
+        client.write_all(b"XYZ\r\n").unwrap();
+        session.handle_command();
+        assert!(reply(&mut client).starts_with("500"));
+        let answer = reply(&mut client);
+        assert!(answer.starts_with("421 Too many failed commands"), "{}", answer);
+        assert!(!session.cmd_conn.connected());
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+    #[test]
+    fn test_transfer_summary() {
+        assert_eq!(transfer_summary(0, Duration::ZERO), " (0 bytes, 0.00 B/s)");
+        assert_eq!(transfer_summary(512, Duration::ZERO), " (512 bytes, 500.00 KB/s)");
+        assert_eq!(
+            transfer_summary(3 << 20, Duration::from_secs(2)),
+            " (3145728 bytes, 1.50 MB/s)"
+        );
+
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        session.config.transfer_summary = true;
+        let path = temp_path("summary");
+        std::fs::write(&path, vec![b'x'; 10000]).unwrap();
+        let (listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let receiver = thread::spawn(move || {
+            let (mut data, _) = listener.accept().unwrap();
+            let mut out = Vec::new();
+            data.read_to_end(&mut out).unwrap();
+            out.len()
+        });
+        assert!(command(&mut session, &mut client, &format!("RETR {}", path.display()))
+            .starts_with("150"));
+        let answer = reply(&mut client);
+        assert_eq!(receiver.join().unwrap(), 10000);
+        assert!(answer.starts_with("226"), "{}", answer);
+        let summary = answer.split_once(" (10000 bytes, ").map(|(_, rest)| rest);
+        let (rate, unit) = summary.and_then(|s| s.split_once(' ')).expect(&answer);
+        assert!(rate.parse::<f64>().unwrap() > 0.0, "{}", answer);
+        assert!(["B/s)\r\n", "KB/s)\r\n", "MB/s)\r\n", "GB/s)\r\n"].contains(&unit), "{}", answer);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_command_log() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        set_user(&mut session, "tester", "secret");
+        let path = temp_path("command_log");
+        let log = Arc::new(CommandLog::open(&path, LogFlush::Interval).unwrap());
+        session.set_command_log(log.clone());
+        assert!(command(&mut session, &mut client, "USER tester").starts_with("331"));
+        assert!(command(&mut session, &mut client, "PASS secret").starts_with("230"));
+        assert!(std::fs::read(&path).unwrap().is_empty());
+        log.flush();
+        let peer = session.peer_name();
+        let expected = format!("{} - USER tester\n{} tester PASS ****\n", peer, peer);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_syst_unix() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        assert_eq!(session.list_format, ListFormat::Unix);
+        assert_eq!(command(&mut session, &mut client, "SYST"), "215 UNIX Type: L8\r\n");
+        session.config.syst_reply = "UNIX Type: L8 Version: miniftp".to_string();
+        let answer = command(&mut session, &mut client, "SYST");
+        assert_eq!(answer, "215 UNIX Type: L8 Version: miniftp\r\n");
+        // A DOS style client would misread the Unix listing
+        session.config.syst_reply = "Windows_NT".to_string();
+        assert_eq!(command(&mut session, &mut client, "SYST"), "215 UNIX Type: L8\r\n");
+    }
+    #[test]
+    fn test_transfer_metrics() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        let metrics = TransferMetrics::new();
+        session.set_metrics(metrics.clone());
+
+        let path = temp_path("metrics");
+        for size in [100, 3000, 4000] {
+            let (listener, port) = data_listener();
+            assert!(command(&mut session, &mut client, &port).starts_with("200"));
+            let sender = thread::spawn(move || {
+                let (mut data, _) = listener.accept().unwrap();
+                data.write_all(&vec![b'x'; size]).unwrap();
+            });
+            let answer = command(&mut session, &mut client, &format!("STOR {}", path.display()));
+            assert!(answer.starts_with("125"), "{}", answer);
+            assert!(reply(&mut client).starts_with("226"));
+            sender.join().unwrap();
+        }
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.sizes.count(), 3);
+        assert_eq!(snapshot.sizes.sum, 7100);
+        assert_eq!(snapshot.sizes.bucket(Some(128)), 1);
+        assert_eq!(snapshot.sizes.bucket(Some(4096)), 2);
+        assert_eq!(snapshot.durations.count(), 3);
+        assert_eq!(snapshot.throughput.count(), 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+    // Keeps the results of the transfers
+    #[derive(Debug, Default)]
+    struct TransferRecorder {
+        results: Mutex<Vec<TransferResult>>,
+    }
+    impl TransferObserver for TransferRecorder {
+        fn transfer_done(&self, result: &TransferResult) {
+            self.results.lock().unwrap().push(result.clone());
+        }
+    }
+    #[test]
+    fn test_transfer_result() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        session.is_admin = true;
+        let recorder = Arc::new(TransferRecorder::default());
+        session.add_transfer_observer(recorder.clone());
+
+        let path = temp_path("transfer_result");
+        let (listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let sender = thread::spawn(move || {
+            let (mut data, _) = listener.accept().unwrap();
+            data.write_all(&[b'x'; 1000]).unwrap();
+        });
+        let result = session.stor(path.clone()).unwrap();
+        assert!(reply(&mut client).starts_with("125"));
+        assert!(reply(&mut client).starts_with("226"));
+        sender.join().unwrap();
+        assert_eq!(result.bytes, 1000);
+        assert_eq!(result.direction, Direction::Upload);
+        assert_eq!(result.path, path);
+        assert_eq!(result.outcome, TransferOutcome::Complete);
+
+        let (listener, port) = data_listener();
+        assert!(command(&mut session, &mut client, &port).starts_with("200"));
+        let receiver = thread::spawn(move || {
+            let (mut data, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            data.read_to_end(&mut buf).unwrap();
+            buf.len()
+        });
+        let result = session.retr(path.clone()).unwrap();
+        assert!(reply(&mut client).starts_with("150"));
+        assert!(reply(&mut client).starts_with("226"));
+        assert_eq!(receiver.join().unwrap(), 1000);
+        assert_eq!(result.bytes, 1000);
+        assert_eq!(result.direction, Direction::Download);
+        assert!(result.is_complete());
+
+        let results = recorder.results.lock().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1], result);
+        assert_eq!(session.metrics.snapshot().sizes.sum, 2000);
+        // No transfer for a missing file
+        assert!(session.retr(temp_path("transfer_result_missing")).is_none());
+        assert!(reply(&mut client).starts_with("550"));
+        std::fs::remove_file(&path).unwrap();
+    }
+    // Passive mode with a free port, return the port
+    fn pasv(session: &mut Session, client: &mut TcpStream) -> u16 {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        session.data_port = Some(port - 2);
+        assert!(command(session, client, "PASV").starts_with("227"));
+        port
+    }
+    #[test]
+    fn test_pasv_pre_connect() {
+        let (mut session, mut client) = new_session(&Config::default(), None);
+        login(&mut session, &mut client);
+        let port = pasv(&mut session, &mut client);
+        let mut data = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert!(command(&mut session, &mut client, "NOOP").starts_with("200"));
+        assert!(session.data.is_held());
+        assert!(!session.data.is_listening());
+
+        let answer = command(&mut session, &mut client, "NLST /");
+        assert!(answer.starts_with("150"), "{}", answer);
+        assert!(reply(&mut client).starts_with("226"));
+        let mut out = String::new();
+        data.read_to_string(&mut out).unwrap();
+        assert!(out.contains("etc\r\n"), "{}", out);
+        assert!(!session.data.is_held());
+    }
+    #[test]
+    fn test_pasv_pre_connect_timeout() {
+        let mut config = Config::default();
+        config.data_connect_timeout = 1;
+        let (mut session, mut client) = new_session(&config, None);
+        login(&mut session, &mut client);
+        let port = pasv(&mut session, &mut client);
+        let mut data = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert!(command(&mut session, &mut client, "NOOP").starts_with("200"));
+        assert!(session.data.is_held());
+
+        // Connected, but no transfer command
+        thread::sleep(std::time::Duration::from_millis(1100));
+        assert!(command(&mut session, &mut client, "NOOP").starts_with("200"));
+        assert!(!session.data.is_held());
+        let mut buf = [0u8; 8];
+        assert_eq!(data.read(&mut buf).unwrap(), 0);
+
+        // A client which never connects
+        pasv(&mut session, &mut client);
+        let answer = command(&mut session, &mut client, "NLST /");
+        assert!(answer.starts_with("426"), "{}", answer);
+    }
+    #[test]
+    fn test_jail_symlinks() {
+        let root = temp_path("jail_root");
+        let outside = temp_path("jail_outside");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("real")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(root.join("real").join("inner.txt"), b"data").unwrap();
+        std::os::unix::fs::symlink(root.join("real"), root.join("link")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("out")).unwrap();
+
+        let mut config = Config::default();
+        config.jail = true;
+        let (mut session, mut client) = new_session(&config, None);
+        login(&mut session, &mut client);
+        session.server_root = canonicalize(&root).unwrap();
+        session.cur_dir = session.server_root.clone();
+
+        // The link in the root is listed and entered to its target
+        let port = pasv(&mut session, &mut client);
+        let mut data = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let answer = command(&mut session, &mut client, "NLST link");
+        assert!(answer.starts_with("150"), "{}", answer);
+        assert!(reply(&mut client).starts_with("226"));
+        let mut out = String::new();
+        data.read_to_string(&mut out).unwrap();
+        assert!(out.contains("inner.txt"), "{}", out);
+        assert!(command(&mut session, &mut client, "CWD link").starts_with("250"));
+        assert_eq!(session.cur_dir, session.server_root.join("real"));
+        assert!(command(&mut session, &mut client, "CDUP").starts_with("250"));
+        assert!(command(&mut session, &mut client, "CDUP").starts_with("550"));
+        assert_eq!(session.cur_dir, session.server_root);
+
+        // The link out of the root is refused
+        assert!(command(&mut session, &mut client, "CWD out").starts_with("550"));
+        let port = pasv(&mut session, &mut client);
+        let mut data = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let answer = command(&mut session, &mut client, "LIST out");
+        assert!(answer.starts_with("550"), "{}", answer);
+        assert_eq!(session.cur_dir, session.server_root);
+        // The data connection wasn't taken by the refused LIST
+        assert!(command(&mut session, &mut client, "NLST real").starts_with("150"));
+        assert!(reply(&mut client).starts_with("226"));
+        let mut out = String::new();
+        data.read_to_string(&mut out).unwrap();
+        assert!(out.contains("inner.txt"), "{}", out);
+
+        // And so it is for the file commands, of existing files and of new ones
+        std::fs::write(outside.join("secret.txt"), b"secret").unwrap();
+        for line in ["STAT out", "SIZE out/secret.txt", "RNFR out/secret.txt", "MKD out/new"] {
+            let answer = command(&mut session, &mut client, line);
+            assert!(answer.starts_with("550 Permission denied"), "{}: {}", line, answer);
+        }
+        assert!(!outside.join("new").exists());
+        assert!(command(&mut session, &mut client, "SIZE link/inner.txt").starts_with("213"));
+
+        session.config.follow_symlinks = false;
+        assert!(command(&mut session, &mut client, "CWD link").starts_with("550"));
+        assert!(command(&mut session, &mut client, "SIZE link/inner.txt").starts_with("550"));
+        assert!(command(&mut session, &mut client, "CWD real").starts_with("250"));
+
+        // Out of the root, without the jail, the links are still found
+        session.config.jail = false;
+        let answer =
+            command(&mut session, &mut client, &format!("SIZE {}/out/secret.txt", root.display()));
+        assert!(answer.starts_with("550"), "{}", answer);
+        let answer =
+            command(&mut session, &mut client, &format!("SIZE {}/secret.txt", outside.display()));
+        assert!(answer.starts_with("213"), "{}", answer);
+
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+}
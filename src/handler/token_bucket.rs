@@ -0,0 +1,57 @@
+use std::time::Instant;
+
+// Allows rate operations per second on average, with bursts up to one second of them
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64, // tokens/s, also the capacity
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+    // Take a token if there's one, refilled for the time since the last call
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(10);
+        let start = bucket.last;
+        assert!((0..10).all(|_| bucket.try_take_at(start)));
+        assert!(!bucket.try_take_at(start));
+        // 2.5 tokens are back
+        let later = start + Duration::from_millis(250);
+        assert!(bucket.try_take_at(later));
+        assert!(bucket.try_take_at(later));
+        assert!(!bucket.try_take_at(later));
+        // No more than a second of them
+        let later = later + Duration::from_secs(5);
+        assert!((0..10).all(|_| bucket.try_take_at(later)));
+        assert!(!bucket.try_take_at(later));
+    }
+}
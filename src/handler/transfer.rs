@@ -0,0 +1,88 @@
+use crate::handler::session::format_size;
+use std::fmt::{self, Debug};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Download, // RETR
+    Upload,   // STOR
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferOutcome {
+    Complete,
+    Aborted, // by an ABOR of the client
+    Failed,  // the data connection or the file failed
+}
+
+// A finished RETR or STOR, the input of the transfer log, the metrics and the observers
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferResult {
+    pub bytes: u64, // of the file, the ASCII translation aside
+    pub duration: Duration,
+    pub direction: Direction,
+    pub path: PathBuf,
+    pub outcome: TransferOutcome,
+}
+
+impl TransferResult {
+    pub fn is_complete(&self) -> bool {
+        self.outcome == TransferOutcome::Complete
+    }
+    // bytes/s
+    pub fn rate(&self) -> f64 {
+        self.bytes as f64 / self.duration.as_secs_f64().max(1e-6)
+    }
+}
+
+// The transfer log line, e.g. RETR /a: 21863760 bytes in 0.30 secs ( 69.50MB/s), complete
+impl fmt::Display for TransferResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verb = match self.direction {
+            Direction::Download => "RETR",
+            Direction::Upload => "STOR",
+        };
+        let outcome = match self.outcome {
+            TransferOutcome::Complete => "complete",
+            TransferOutcome::Aborted => "aborted",
+            TransferOutcome::Failed => "failed",
+        };
+        write!(
+            f,
+            "{} {}: {} bytes in {:.2} secs ({}B/s), {}",
+            verb,
+            self.path.display(),
+            self.bytes,
+            self.duration.as_secs_f64(),
+            format_size(self.rate()),
+            outcome
+        )
+    }
+}
+
+// Told of every finished transfer of the sessions, e.g. by an embedder
+pub trait TransferObserver: Debug + Send + Sync {
+    fn transfer_done(&self, result: &TransferResult);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfer_line() {
+        let result = TransferResult {
+            bytes: 3 << 20,
+            duration: Duration::from_secs(2),
+            direction: Direction::Upload,
+            path: PathBuf::from("/tmp/a"),
+            outcome: TransferOutcome::Aborted,
+        };
+        assert_eq!(result.rate(), (3 << 19) as f64);
+        assert_eq!(
+            result.to_string(),
+            "STOR /tmp/a: 3145728 bytes in 2.00 secs (  1.50MB/s), aborted"
+        );
+    }
+}
@@ -1,4 +1,10 @@
 use super::socket::Socket;
+use log::{debug, warn};
+use nix::errno::Errno;
+use nix::sys::socket::getpeername;
+use nix::unistd::close;
+use std::os::unix::prelude::AsRawFd;
+
 pub struct Acceptor {
     accept_socket: Socket,
     listening: bool,
@@ -20,4 +26,81 @@ impl Acceptor {
         sock.set_keep_alive(true);
         sock
     }
+    // The listener is edge-triggered, so all the pending connections must be
+    // accepted in one wakeup, until EAGAIN. The listen fd must be nonblocking.
+    // Out of fds, the rest stays pending and the EMFILE or ENFILE is returned too.
+    pub fn accept_pending(listen_fd: i32) -> (Vec<Socket>, Option<Errno>) {
+        let mut socks = Vec::new();
+        loop {
+            match Socket::try_accept(listen_fd) {
+                // The client reset between the accept and now, it's benign under load
+                Ok(sock) if getpeername(sock.as_raw_fd()) == Err(Errno::ENOTCONN) => {
+                    debug!("Skip connection {} reset by its peer", sock.as_raw_fd());
+                    close(sock.as_raw_fd()).unwrap_or_default();
+                }
+                Ok(mut sock) => {
+                    sock.set_no_delay(true);
+                    sock.set_keep_alive(true);
+                    socks.push(sock);
+                }
+                Err(Errno::EAGAIN) => break,
+                // The client gave up before the accept
+                Err(Errno::ECONNABORTED) => continue,
+                Err(e @ Errno::EMFILE) | Err(e @ Errno::ENFILE) => {
+                    warn!("Accept on {} stopped: {}, {} accepted", listen_fd, e, socks.len());
+                    return (socks, Some(e));
+                }
+                Err(e) => {
+                    warn!("Accept on {} failed: {}", listen_fd, e);
+                    break;
+                }
+            }
+        }
+        (socks, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::event_loop::{EVENT_LEVEL, EVENT_READ};
+    use crate::net::poller::Poller;
+    use nix::sys::socket::{setsockopt, sockopt};
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_accept_pending() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let mut poller = Poller::new();
+        poller.register(listener.as_raw_fd(), EVENT_READ | EVENT_LEVEL);
+
+        let addr = listener.local_addr().unwrap();
+        let clients = (0..5).map(|_| TcpStream::connect(addr).unwrap()).collect::<Vec<_>>();
+        // One edge for all the connections
+        assert_eq!(poller.poll(), 1);
+        let (socks, error) = Acceptor::accept_pending(listener.as_raw_fd());
+        assert_eq!(socks.len(), clients.len());
+        assert_eq!(error, None);
+        assert!(Acceptor::accept_pending(listener.as_raw_fd()).0.is_empty());
+        socks.iter().for_each(|sock| close(sock.as_raw_fd()).unwrap());
+    }
+    #[test]
+    fn test_accept_reset_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let reset = TcpStream::connect(addr).unwrap();
+        let linger = nix::libc::linger { l_onoff: 1, l_linger: 0 };
+        setsockopt(reset.as_raw_fd(), sockopt::Linger, &linger).unwrap();
+        drop(reset); // RST instead of FIN
+        let client = TcpStream::connect(addr).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let (socks, _) = Acceptor::accept_pending(listener.as_raw_fd());
+        assert_eq!(socks.len(), 1);
+        let peer = getpeername(socks[0].as_raw_fd()).unwrap();
+        assert_eq!(peer.to_string(), client.local_addr().unwrap().to_string());
+        close(socks[0].as_raw_fd()).unwrap();
+    }
 }
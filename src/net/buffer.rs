@@ -1,3 +1,4 @@
+use crate::utils::retry::retry_eintr;
 use log::{debug, error};
 use nix::sys::uio::{readv, IoVec};
 use std::{fmt, ptr};
@@ -34,7 +35,7 @@ impl Buffer {
         self.write_index = 0;
         self.data.resize(DEFAULT_INIT_SIZE, 0u8);
     }
-    // Read data to buffer for file description
+    // Read data to buffer for file description, None if reading failed without any data
     pub fn read(&mut self, fd: i32) -> Option<usize> {
         let mut extrabuf = [0u8; 1024 * 64];
         let mut len = 0usize;
@@ -47,7 +48,7 @@ impl Buffer {
                 IoVec::from_mut_slice(&mut self.data[self.write_index..]),
                 IoVec::from_mut_slice(&mut extrabuf),
             ];
-            match readv(fd, &mut iov) {
+            match retry_eintr(|| readv(fd, &mut iov)) {
                 Ok(0) => {
                     error!("Read len: 0");
                 }
@@ -67,6 +68,9 @@ impl Buffer {
                 }
                 Err(e) => {
                     error!("Read error: {}", e);
+                    if len == 0 {
+                        return None;
+                    }
                 }
             }
         }
@@ -89,6 +93,10 @@ impl Buffer {
         }
         None
     }
+    // The next CRLF line, left in the buffer
+    pub fn peek_crlf_line(&self) -> Option<&[u8]> {
+        self.find_crlf().map(|n| &self.bytes()[..n + 2])
+    }
     pub fn read_buf(&mut self) -> Vec<u8> {
         let buf = self.bytes().to_vec();
         self.read_index += buf.len();
@@ -125,6 +133,9 @@ impl Buffer {
             assert_eq!(readable, self.readable_bytes());
         }
     }
+    pub fn is_empty(&self) -> bool {
+        self.write_index == self.read_index
+    }
     // 可写区间大小
     fn writable_bytes(&self) -> usize {
         self.data.len() - self.write_index
@@ -149,12 +160,22 @@ impl Buffer {
         self.data.len() - self.readable_bytes()
     }
     // 可读区域
-    fn bytes<'a>(&'a self) -> &'a [u8] {
+    pub fn bytes<'a>(&'a self) -> &'a [u8] {
         &self.data[self.read_index..self.write_index]
     }
+    // Drop the first n readable bytes, e.g. once they're written
+    pub fn consume(&mut self, n: usize) {
+        self.read_index += n.min(self.write_index - self.read_index);
+    }
     fn find_eol(&self) -> Option<usize> {
         self.bytes().iter().position(|&b| b == b'\n')
     }
+    pub fn count_crlf(&self) -> usize {
+        self.bytes()
+            .windows(2)
+            .filter(|bytes| bytes == b"\r\n")
+            .count()
+    }
     fn find_crlf(&self) -> Option<usize> {
         self.bytes().windows(2).position(|bytes| bytes == b"\r\n")
     }
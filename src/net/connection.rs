@@ -2,16 +2,42 @@ use super::buffer::Buffer;
 use super::event_loop::EventLoop;
 use super::event_loop::*;
 use super::socket::Socket;
-use log::warn;
-use nix::fcntl::{fcntl, open, FcntlArg, OFlag};
+use crate::utils::retry::retry_eintr;
+use log::{debug, warn};
+use nix::fcntl::{open, splice, OFlag, SpliceFFlags};
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::errno::Errno;
 use nix::sys::epoll::EpollFlags;
 use nix::sys::sendfile::sendfile;
 use nix::sys::socket::Shutdown;
-use nix::sys::socket::{getpeername, getsockname, shutdown};
+use nix::sys::socket::{getpeername, getsockname, recv, setsockopt, shutdown, sockopt};
+use nix::sys::socket::{MsgFlags, SockAddr};
 use nix::sys::stat::Mode;
 use nix::unistd::{close, write};
+use std::fmt::Debug;
+use std::net::IpAddr;
 use std::os::unix::prelude::AsRawFd;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+// Zero length writes in a row before the peer is considered gone
+const MAX_ZERO_WRITES: usize = 3;
+const SPLICE_CHUNK: usize = 64 * 1024; // the default pipe capacity
+
+// The ids in the logs, a data connection is tagged after its control connection
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    // The transfer progress is stamped in milliseconds since this instant
+    static ref CLOCK: Instant = Instant::now();
+}
+
+fn clock_ms() -> u64 {
+    CLOCK.elapsed().as_millis() as u64
+}
 
 pub type ConnRef = Arc<Mutex<Connection>>;
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -23,11 +49,90 @@ pub enum State {
     Closed,
 }
 
+// Progress of the transfer on a data connection
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TransferState {
+    Idle,
+    Sending,
+    Receiving,
+    Aborting,
+    Done,
+}
+
+impl TransferState {
+    pub fn can_move_to(&self, next: TransferState) -> bool {
+        use TransferState::*;
+        matches!(
+            (*self, next),
+            (Idle, Sending)
+                | (Idle, Receiving)
+                | (Sending, Aborting)
+                | (Receiving, Aborting)
+                | (Sending, Done)
+                | (Receiving, Done)
+                | (Aborting, Done)
+        )
+    }
+    pub fn is_active(&self) -> bool {
+        matches!(*self, TransferState::Sending | TransferState::Receiving)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    state: AtomicU8,
+    bytes: AtomicU64,
+    total: AtomicU64, // 0 if unknown
+    stamp: AtomicU64, // clock_ms of the latest progress
+}
+
+// Shared view of a transfer state, it can be read while the session is busy in a transfer loop
+#[derive(Debug, Clone, Default)]
+pub struct TransferTracker(Arc<Progress>);
+
+impl TransferTracker {
+    pub fn new() -> Self {
+        TransferTracker::default()
+    }
+    pub fn get(&self) -> TransferState {
+        match self.0.state.load(Ordering::SeqCst) {
+            0 => TransferState::Idle,
+            1 => TransferState::Sending,
+            2 => TransferState::Receiving,
+            3 => TransferState::Aborting,
+            _ => TransferState::Done,
+        }
+    }
+    fn set(&self, state: TransferState) {
+        self.0.state.store(state as u8, Ordering::SeqCst);
+        self.stamp();
+    }
+    fn stamp(&self) {
+        self.0.stamp.store(clock_ms(), Ordering::SeqCst);
+    }
+    // An active transfer which moved some bytes within the period
+    pub fn progressed_within(&self, period: Duration) -> bool {
+        let elapsed = clock_ms().saturating_sub(self.0.stamp.load(Ordering::SeqCst));
+        self.get().is_active() && elapsed <= period.as_millis() as u64
+    }
+    // Transferred bytes and the total size if it's known
+    pub fn progress(&self) -> (u64, Option<u64>) {
+        let total = self.0.total.load(Ordering::SeqCst);
+        (self.0.bytes.load(Ordering::SeqCst), if total > 0 { Some(total) } else { None })
+    }
+    pub fn reset(&self) {
+        self.set(TransferState::Idle);
+        self.0.bytes.store(0, Ordering::SeqCst);
+        self.0.total.store(0, Ordering::SeqCst);
+    }
+}
+
 const READABLE: u8 = 0b0001;
 const WRITABLE: u8 = 0b0010;
 
 pub trait EventSet {
     fn is_readable(&self) -> bool;
+    fn is_urgent(&self) -> bool;
     fn is_writeable(&self) -> bool;
     fn is_close(&self) -> bool;
     fn is_error(&self) -> bool;
@@ -37,6 +142,9 @@ impl EventSet for EpollFlags {
     fn is_readable(&self) -> bool {
         (*self & (EpollFlags::EPOLLIN | EpollFlags::EPOLLPRI)).bits() > 0
     }
+    fn is_urgent(&self) -> bool {
+        (*self & EpollFlags::EPOLLPRI).bits() > 0
+    }
     fn is_writeable(&self) -> bool {
         (*self & EpollFlags::EPOLLOUT).bits() > 0
     }
@@ -51,32 +159,90 @@ impl EventSet for EpollFlags {
     }
 }
 
-#[derive(Debug, Clone)]
+// Where the data is sent, the socket itself except in the tests
+pub trait Transport: Debug + Send {
+    fn write(&mut self, buf: &[u8]) -> nix::Result<usize>;
+}
+
+impl Transport for Socket {
+    fn write(&mut self, buf: &[u8]) -> nix::Result<usize> {
+        retry_eintr(|| write(self.as_raw_fd(), buf))
+    }
+}
+
+// Owns the socket, the fd is closed when the connection is dropped
+#[derive(Debug)]
 pub struct Connection {
     sock: Socket,
+    id: String,
+    transport: Box<dyn Transport>,
     state: State,
     input_buf: Buffer,
     output_buf: Buffer,
     local_addr: String,
     peer_addr: String,
+    peer_ip: Option<IpAddr>,
+    local_ip: Option<IpAddr>,
+    local_port: u16, // 0 if it isn't an inet socket
     revents: EpollFlags,
+    transfer: TransferTracker,
+    owner: ThreadId, // the only thread doing IO, see attach_to_current_thread
 }
 
 impl Connection {
     pub fn new(sock: Socket) -> Self {
         assert!(sock.as_raw_fd() > 0);
-        let local_addr = format!("{}", getsockname(sock.as_raw_fd()).unwrap());
-        let peer_addr = format!("{}", getpeername(sock.as_raw_fd()).unwrap());
+        let local = getsockname(sock.as_raw_fd()).unwrap();
+        let local_addr = format!("{}", local);
+        let peer = getpeername(sock.as_raw_fd()).unwrap();
+        let peer_addr = format!("{}", peer);
         Connection {
+            transport: Box::new(sock.clone()),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed).to_string(),
             sock,
             state: State::Ready,
             input_buf: Buffer::new(),
             output_buf: Buffer::new(),
             local_addr,
             peer_addr,
+            peer_ip: inet_ip(&peer),
+            local_ip: inet_ip(&local),
+            local_port: inet_port(&local),
             revents: EpollFlags::empty(),
+            transfer: TransferTracker::new(),
+            owner: thread::current().id(),
         }
     }
+    // Hand the connection over to the calling thread, e.g. the worker holding
+    // the session lock. The IO from any other thread is a bug.
+    pub fn attach_to_current_thread(&mut self) {
+        self.owner = thread::current().id();
+    }
+    pub fn assert_in_loop_thread(&self) {
+        debug_assert!(
+            self.owner == thread::current().id(),
+            "Connection {} is owned by thread {:?}, but used by thread {:?}",
+            self.sock.as_raw_fd(),
+            self.owner,
+            thread::current().id()
+        );
+    }
+    // Drop what's left from the previous user of the connection: the buffered
+    // bytes, the state and the transfer counters.
+    pub fn reset_for_reuse(&mut self) {
+        self.assert_in_loop_thread();
+        self.input_buf.reset();
+        self.output_buf.reset();
+        self.state = State::Ready;
+        self.revents = EpollFlags::empty();
+        self.transfer = TransferTracker::new();
+    }
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    pub fn set_id(&mut self, id: String) {
+        self.id = id;
+    }
     pub fn set_revents(&mut self, revents: &EpollFlags) {
         self.revents = revents.clone();
     }
@@ -92,6 +258,64 @@ impl Connection {
     pub fn get_local_addr(&self) -> String {
         self.local_addr.clone()
     }
+    // None for the unix domain socket
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_ip
+    }
+    // The address of the listener the connection arrived on
+    pub fn local_ip(&self) -> Option<IpAddr> {
+        self.local_ip
+    }
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+    pub fn transfer_state(&self) -> TransferState {
+        self.transfer.get()
+    }
+    // Maintained by the transfer loops
+    pub fn set_transfer_state(&mut self, state: TransferState) {
+        let old = self.transfer.get();
+        debug_assert!(old.can_move_to(state), "Transfer state {:?} -> {:?}", old, state);
+        debug!("[{}] Transfer state: {:?} -> {:?}", self.id, old, state);
+        self.transfer.set(state);
+    }
+    // Report the transfer state to the tracker from now on, starting at Idle
+    pub fn track_transfer(&mut self, tracker: TransferTracker) {
+        tracker.reset();
+        self.transfer = tracker;
+    }
+    pub fn set_transfer_total(&mut self, total: u64) {
+        self.transfer.0.total.store(total, Ordering::SeqCst);
+    }
+    pub fn add_transferred(&mut self, n: usize) {
+        self.transfer.0.bytes.fetch_add(n as u64, Ordering::SeqCst);
+        self.transfer.stamp();
+    }
+    // Check without blocking whether some data is waiting to be read
+    pub fn poll_readable(&self) -> bool {
+        let mut fds = [PollFd::new(self.sock.as_raw_fd(), PollFlags::POLLIN)];
+        matches!(poll(&mut fds, 0), Ok(n) if n > 0)
+    }
+    // Check without blocking whether an urgent byte is waiting
+    pub fn poll_urgent(&self) -> bool {
+        let mut fds = [PollFd::new(self.sock.as_raw_fd(), PollFlags::POLLPRI)];
+        matches!(poll(&mut fds, 0), Ok(n) if n > 0)
+    }
+    // Take the urgent byte, the DM of the TELNET synch sent before ABOR. It's out of
+    // the stream, so it may come apart from the in-band IAC IP and the ABOR line.
+    // None if there's none, or if the in-band reads went past it already.
+    pub fn recv_urgent(&mut self) -> Option<u8> {
+        self.assert_in_loop_thread();
+        let mut buf = [0u8; 1];
+        let flags = MsgFlags::MSG_OOB | MsgFlags::MSG_DONTWAIT;
+        match retry_eintr(|| recv(self.sock.as_raw_fd(), &mut buf, flags)) {
+            Ok(1) => {
+                debug!("[{}] Urgent byte {:#x}", self.id, buf[0]);
+                Some(buf[0])
+            }
+            _ => None,
+        }
+    }
     pub fn dispatch(&mut self, revents: EpollFlags) -> State {
         self.state = State::Ready;
         if revents.is_readable() {
@@ -117,9 +341,21 @@ impl Connection {
     pub fn register_read(&mut self, event_loop: &mut EventLoop) {
         event_loop.reregister(
             self.sock.as_raw_fd(),
-            EVENT_HUP | EVENT_ERR | EVENT_WRIT | EVENT_READ | EVENT_LEVEL,
+            EVENT_HUP | EVENT_ERR | EVENT_WRIT | EVENT_READ | EVENT_PRI | EVENT_LEVEL,
         );
     }
+    // Ask for another notification. The connection is writable most of the time,
+    // so the loop wakes it up soon even if nothing more is received.
+    pub fn rearm(&self, event_loop: &EventLoop) {
+        let fd = self.sock.as_raw_fd();
+        if event_loop.is_registered(fd) {
+            let interest = EVENT_HUP | EVENT_ERR | EVENT_WRIT | EVENT_READ | EVENT_PRI;
+            event_loop.rearm(fd, interest | EVENT_LEVEL);
+        }
+    }
+    pub fn set_transport(&mut self, transport: Box<dyn Transport>) {
+        self.transport = transport;
+    }
     pub fn deregister(&mut self, event_loop: &mut EventLoop) {
         event_loop.deregister(self.sock.as_raw_fd());
         self.shutdown();
@@ -128,9 +364,19 @@ impl Connection {
         self.state = State::Closed;
         match shutdown(self.sock.as_raw_fd(), Shutdown::Both) {
             Ok(()) => (),
-            Err(e) => warn!("Shutdown {} occur {} error", self.sock.as_raw_fd(), e),
+            Err(e) => warn!("[{}] Shutdown {} occur {} error", self.id, self.sock.as_raw_fd(), e),
         }
     }
+    // Close at once with a reset instead of a FIN, e.g. to refuse a client without
+    // any reply. The socket doesn't linger in TIME_WAIT.
+    pub fn abort(mut self) {
+        let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+        if let Err(e) = setsockopt(self.sock.as_raw_fd(), sockopt::Linger, &linger) {
+            warn!("[{}] Couldn't set SO_LINGER: {}", self.id, e);
+        }
+        // No shutdown, the close by drop sends the reset
+        self.state = State::Closed;
+    }
     // 限速发送，定时发送一部分
     pub fn send_file(
         &mut self,
@@ -139,42 +385,186 @@ impl Connection {
         off: Option<i64>,
         size: usize,
     ) -> Option<usize> {
+        self.assert_in_loop_thread();
         let mut off64 = off.unwrap_or(0);
-        let off = if off.is_none() { None } else { Some(&mut off64) };
-        if let Some(file) = file {
+        let mut off = if off.is_none() { None } else { Some(&mut off64) };
+        let sock = self.sock.as_raw_fd();
+        let result = if let Some(file) = file {
             let fd = open(file, OFlag::O_RDWR, Mode::S_IRUSR).unwrap();
-            let size = sendfile(self.sock.as_raw_fd(), fd, off, size).unwrap();
+            let result = retry_eintr(|| sendfile(sock, fd, off.as_deref_mut(), size));
             close(fd).expect("Couldn't close file");
-            return Some(size);
+            result
         } else {
-            let size = sendfile(self.sock.as_raw_fd(), fd, off, size).unwrap();
-            return Some(size);
+            retry_eintr(|| sendfile(sock, fd, off.as_deref_mut(), size))
+        };
+        match result {
+            Ok(size) => Some(size),
+            Err(e) => {
+                warn!("[{}] Send file error: {}", self.id, e);
+                None
+            }
         }
     }
-    pub fn send(&mut self, buf: &[u8]) {
-        match write(self.sock.as_raw_fd(), buf) {
-            Ok(_) => (),
-            Err(e) => {
-                warn!("Send data error: {}", e)
+    // Move the data of a pipe to the socket in the kernel until its end,
+    // the count of bytes or None on error
+    pub fn splice_from(&mut self, pipe: i32) -> Option<usize> {
+        self.assert_in_loop_thread();
+        let flags = SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_MORE;
+        let sock = self.sock.as_raw_fd();
+        let mut total = 0;
+        loop {
+            match retry_eintr(|| splice(pipe, None, sock, None, SPLICE_CHUNK, flags)) {
+                Ok(0) => return Some(total),
+                Ok(n) => {
+                    total += n;
+                    self.add_transferred(n);
+                }
+                Err(e) => {
+                    warn!("[{}] Splice error: {}", self.id, e);
+                    return None;
+                }
             }
-        };
+        }
+    }
+    // Return false if the data isn't sent completely
+    pub fn send(&mut self, buf: &[u8]) -> bool {
+        self.assert_in_loop_thread();
+        let mut len = 0;
+        let mut zero_writes = 0;
+        while len < buf.len() {
+            match self.transport.write(&buf[len..]) {
+                // Nothing is accepted, retrying forever would spin
+                Ok(0) => {
+                    zero_writes += 1;
+                    if zero_writes >= MAX_ZERO_WRITES {
+                        warn!("[{}] {} writes of 0 bytes, close it", self.id, zero_writes);
+                        self.shutdown();
+                        return false;
+                    }
+                }
+                Ok(n) => {
+                    zero_writes = 0;
+                    len += n;
+                }
+                Err(e) => {
+                    warn!("[{}] Send data error: {}", self.id, e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+    // Queue the data behind the unsent output, then write what the socket takes. The
+    // rest is written by flush_output once the socket is writable again, so a reply,
+    // multi-line or not, goes out whole and before the next one. False on failure.
+    pub fn send_queued(&mut self, buf: &[u8]) -> bool {
+        self.assert_in_loop_thread();
+        self.output_buf.append(buf);
+        self.flush_output()
+    }
+    // Write the queued output until it's all sent or the socket is full
+    pub fn flush_output(&mut self) -> bool {
+        self.assert_in_loop_thread();
+        let mut zero_writes = 0;
+        while !self.output_buf.is_empty() {
+            match self.transport.write(self.output_buf.bytes()) {
+                Ok(0) => {
+                    zero_writes += 1;
+                    if zero_writes >= MAX_ZERO_WRITES {
+                        warn!("[{}] {} writes of 0 bytes, close it", self.id, zero_writes);
+                        self.shutdown();
+                        return false;
+                    }
+                }
+                Ok(n) => {
+                    zero_writes = 0;
+                    self.output_buf.consume(n);
+                }
+                Err(Errno::EAGAIN) => {
+                    let pending = self.output_buf.bytes().len();
+                    debug!("[{}] Socket full, {} bytes wait for EPOLLOUT", self.id, pending);
+                    return true;
+                }
+                Err(e) => {
+                    warn!("[{}] Send data error: {}", self.id, e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+    // Flush the queued output before a close, waiting up to timeout for the socket to
+    // take it. False if some of it is left.
+    pub fn drain_output(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.flush_output() && self.has_pending_output() {
+            let left = deadline.saturating_duration_since(Instant::now()).as_millis() as i32;
+            let mut fds = [PollFd::new(self.sock.as_raw_fd(), PollFlags::POLLOUT)];
+            match poll(&mut fds, left) {
+                Ok(0) => break,
+                Ok(_) | Err(Errno::EINTR) => (),
+                Err(_) => break,
+            }
+        }
+        !self.has_pending_output()
+    }
+    pub fn has_pending_output(&self) -> bool {
+        !self.output_buf.is_empty()
     }
     pub fn read_buf(&mut self) -> Vec<u8> {
+        self.assert_in_loop_thread();
         self.input_buf.read(self.sock.as_raw_fd());
         self.input_buf.read_buf()
     }
+    // Empty data for the end of stream, None for a connection error
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        self.assert_in_loop_thread();
+        self.input_buf.read(self.sock.as_raw_fd())?;
+        Some(self.input_buf.read_buf())
+    }
     pub fn read_msg(&mut self) -> Option<Vec<u8>> {
-        match self.input_buf.read(self.sock.as_raw_fd()) {
+        match self.fill_input() {
             Some(0) | None => None,
-            Some(_) => self.input_buf.get_crlf_line(),
+            Some(_) => self.next_line(),
         }
     }
+    // Read the socket into the input buffer without taking a line
+    pub fn fill_input(&mut self) -> Option<usize> {
+        self.assert_in_loop_thread();
+        self.input_buf.read(self.sock.as_raw_fd())
+    }
+    pub fn next_line(&mut self) -> Option<Vec<u8>> {
+        self.input_buf.get_crlf_line()
+    }
+    pub fn peek_line(&self) -> Option<&[u8]> {
+        self.input_buf.peek_crlf_line()
+    }
+    // Complete lines waiting in the input buffer
+    pub fn pending_lines(&self) -> usize {
+        self.input_buf.count_crlf()
+    }
+}
+fn inet_ip(addr: &SockAddr) -> Option<IpAddr> {
+    match addr {
+        SockAddr::Inet(inet) => Some(inet.ip().to_std()),
+        _ => None,
+    }
+}
+fn inet_port(addr: &SockAddr) -> u16 {
+    match addr {
+        SockAddr::Inet(inet) => inet.port(),
+        _ => 0,
+    }
 }
+
 impl Drop for Connection {
     fn drop(&mut self) {
-        if 0 > fcntl(self.sock.as_raw_fd(), FcntlArg::F_GETFL).unwrap() {
+        if self.state != State::Closed {
             self.shutdown();
         }
+        if let Err(e) = close(self.sock.as_raw_fd()) {
+            warn!("[{}] Close {} occur {} error", self.id, self.sock.as_raw_fd(), e);
+        }
     }
 }
 #[cfg(test)]
@@ -183,6 +573,7 @@ mod tests {
     use nix::sys::socket::*;
     use std::cell::RefCell;
     use std::rc::Rc;
+    use std::sync::atomic::AtomicUsize;
     #[test]
     fn test_send_rev_msg() {
         let (rev, send) = socketpair(
@@ -202,4 +593,158 @@ mod tests {
     }
     #[test]
     fn test_send_rev_file() {}
+    #[test]
+    fn test_abort() {
+        use std::io::{ErrorKind, Read};
+        use std::net::{TcpListener, TcpStream};
+        use std::os::unix::prelude::IntoRawFd;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let conn = Connection::new(Socket(server.into_raw_fd()));
+        conn.abort();
+        let mut buf = [0u8; 8];
+        let err = client.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    }
+    #[test]
+    fn test_recv_urgent() {
+        use std::io::Write;
+        use std::net::{TcpListener, TcpStream};
+        use std::os::unix::prelude::IntoRawFd;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let mut conn = Connection::new(Socket(server.into_raw_fd()));
+        assert_eq!(conn.recv_urgent(), None);
+
+        client.write_all(b"\xff\xf4\xff").unwrap();
+        send(client.as_raw_fd(), b"\xf2", MsgFlags::MSG_OOB).unwrap();
+        client.write_all(b"ABOR\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(conn.poll_urgent());
+        assert_eq!(conn.recv_urgent(), Some(0xf2));
+        assert!(!conn.poll_urgent());
+        // A read stops at the urgent mark, the byte itself is out of the stream
+        assert_eq!(conn.fill_input(), Some(3));
+        assert_eq!(conn.fill_input(), Some(6));
+        assert_eq!(conn.next_line(), Some(b"\xff\xf4\xffABOR\r\n".to_vec()));
+    }
+    // Accepts the given number of bytes per write, then nothing
+    #[derive(Debug)]
+    struct MockTransport {
+        accepted: Vec<usize>,
+        writes: Arc<AtomicUsize>,
+    }
+    impl Transport for MockTransport {
+        fn write(&mut self, buf: &[u8]) -> nix::Result<usize> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            Ok(if self.accepted.is_empty() { 0 } else { self.accepted.remove(0).min(buf.len()) })
+        }
+    }
+    #[test]
+    fn test_send_zero_write() {
+        use std::os::unix::{net::UnixStream, prelude::IntoRawFd};
+        let (local, _peer) = UnixStream::pair().unwrap();
+        let mut conn = Connection::new(Socket(local.into_raw_fd()));
+        let writes = Arc::new(AtomicUsize::new(0));
+        let accepted = vec![2, 0, 0, 3];
+        let mock = MockTransport { accepted, writes: writes.clone() };
+        conn.set_transport(Box::new(mock));
+        // The zero writes in between don't end the flush
+        assert!(conn.send(b"hello"));
+        assert_eq!(writes.load(Ordering::SeqCst), 4);
+        assert!(conn.connected());
+
+        writes.store(0, Ordering::SeqCst);
+        assert!(!conn.send(b"spin"));
+        assert_eq!(writes.load(Ordering::SeqCst), MAX_ZERO_WRITES);
+        assert!(!conn.connected());
+    }
+    #[test]
+    fn test_reset_for_reuse() {
+        use std::io::Write;
+        use std::os::unix::{net::UnixStream, prelude::IntoRawFd};
+        let (local, mut peer) = UnixStream::pair().unwrap();
+        let mut conn = Connection::new(Socket(local.into_raw_fd()));
+        peer.write_all(b"USER stale\r\nPASS").unwrap();
+        conn.dispatch(EpollFlags::EPOLLIN);
+        conn.output_buf.append(b"331 Password required\r\n");
+        conn.add_transferred(1024);
+        conn.state = State::Writing;
+        assert!(!conn.input_buf.is_empty() && !conn.output_buf.is_empty());
+
+        conn.reset_for_reuse();
+        assert!(conn.input_buf.is_empty());
+        assert!(conn.output_buf.is_empty());
+        assert_eq!(conn.get_state(), State::Ready);
+        assert_eq!(conn.transfer.progress(), (0, None));
+
+        peer.write_all(b"NOOP\r\n").unwrap();
+        assert_eq!(conn.read_msg(), Some(b"NOOP\r\n".to_vec()));
+    }
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_wrong_thread() {
+        use std::io::Write;
+        use std::os::unix::{net::UnixStream, prelude::IntoRawFd};
+        let (local, mut peer) = UnixStream::pair().unwrap();
+        let mut conn = Connection::new(Socket(local.into_raw_fd()));
+        let (conn, result) = std::thread::spawn(move || {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                conn.send(b"220 Ready\r\n");
+            }));
+            (conn, result)
+        })
+        .join()
+        .unwrap();
+        let err = result.unwrap_err();
+        let message = err.downcast_ref::<String>().unwrap();
+        assert!(message.contains("is owned by thread"), "{}", message);
+
+        // Handed over to the worker, as a session does
+        peer.write_all(b"NOOP\r\n").unwrap();
+        let mut conn = std::thread::spawn(move || {
+            let mut conn = conn;
+            conn.attach_to_current_thread();
+            assert_eq!(conn.read_msg(), Some(b"NOOP\r\n".to_vec()));
+            conn
+        })
+        .join()
+        .unwrap();
+        conn.attach_to_current_thread();
+        assert!(conn.send(b"200 NOOP ok\r\n"));
+    }
+    #[test]
+    fn test_local_addr() {
+        use std::net::{TcpListener, TcpStream};
+        use std::os::unix::prelude::IntoRawFd;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let conn = Connection::new(Socket(stream.into_raw_fd()));
+        assert_eq!(conn.local_ip(), Some(addr.ip()));
+        assert_eq!(conn.local_port(), addr.port());
+        assert_eq!(conn.get_local_addr(), addr.to_string());
+    }
+    #[test]
+    fn test_transfer_state() {
+        use std::os::unix::{net::UnixStream, prelude::IntoRawFd};
+        let (local, _peer) = UnixStream::pair().unwrap();
+        let mut conn = Connection::new(Socket(local.into_raw_fd()));
+        let tracker = TransferTracker::new();
+        conn.track_transfer(tracker.clone());
+        assert_eq!(tracker.get(), TransferState::Idle);
+
+        conn.set_transfer_state(TransferState::Receiving);
+        assert!(conn.transfer_state().is_active());
+        conn.set_transfer_state(TransferState::Aborting);
+        assert_eq!(tracker.get(), TransferState::Aborting);
+        conn.set_transfer_state(TransferState::Done);
+        assert_eq!(tracker.get(), TransferState::Done);
+
+        assert!(!TransferState::Done.can_move_to(TransferState::Sending));
+        assert!(!TransferState::Idle.can_move_to(TransferState::Aborting));
+    }
 }
@@ -4,14 +4,17 @@ use nix::sys::epoll::{EpollEvent, EpollFlags, EpollOp};
 use nix::sys::time::{TimeSpec, TimeValLike};
 use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
 use std::collections::{HashMap, HashSet};
-use std::os::unix::prelude::AsRawFd;
+use std::os::unix::prelude::{AsRawFd, RawFd};
 use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::Duration;
 
 pub const EVENT_LEVEL: EpollFlags = EpollFlags::EPOLLET;
 pub const EVENT_READ: EpollFlags = EpollFlags::EPOLLIN;
 pub const EVENT_ERR: EpollFlags = EpollFlags::EPOLLERR;
 pub const EVENT_HUP: EpollFlags = EpollFlags::EPOLLHUP;
 pub const EVENT_WRIT: EpollFlags = EpollFlags::EPOLLOUT;
+pub const EVENT_PRI: EpollFlags = EpollFlags::EPOLLPRI; // urgent data, e.g. the TELNET synch
 
 #[derive(Debug, Clone, Copy)]
 pub enum Token {
@@ -30,10 +33,11 @@ pub trait Handler: Sized {
 #[derive(Debug, Clone)]
 pub struct EventLoop {
     listener: Arc<Socket>,
-    listeners: Arc<Mutex<HashSet<i32>>>,
+    listeners: Arc<Mutex<HashSet<i32>>>, // fds registered by the connections
     timers: Arc<Mutex<HashMap<i32, TimerFd>>>,
     poller: Poller,
     run: bool,
+    owner: ThreadId, // the thread running the loop
 }
 
 impl EventLoop {
@@ -47,11 +51,22 @@ impl EventLoop {
             timers: Arc::new(Mutex::new(HashMap::new())),
             run: true,
             poller,
+            owner: thread::current().id(),
         }
     }
+    // The registration is shared with the workers, but polling, timers
+    // and quit belong to the loop thread.
+    pub fn assert_in_loop_thread(&self) {
+        debug_assert!(
+            self.owner == thread::current().id(),
+            "EventLoop is owned by thread {:?}, but used by thread {:?}",
+            self.owner,
+            thread::current().id()
+        );
+    }
     pub fn register(&mut self, listener: Socket, interest: EpollFlags) {
         let fd = listener.as_raw_fd();
-        // self.listeners.lock().unwrap().insert(listener);
+        self.listeners.lock().unwrap().insert(fd);
         self.poller.register(fd, interest);
     }
     pub fn register_listen(&mut self, listener: Socket) {
@@ -59,12 +74,43 @@ impl EventLoop {
     }
     pub fn reregister(&self, fd: i32, interest: EpollFlags) {
         let event = EpollEvent::new(interest, fd as u64);
+        self.listeners.lock().unwrap().insert(fd);
         self.poller
             .update(EpollOp::EpollCtlAdd, fd, &mut Some(event));
     }
+    // Modify a registered fd, an edge-triggered fd is notified again if it's still ready
+    pub fn rearm(&self, fd: i32, interest: EpollFlags) {
+        let event = EpollEvent::new(interest, fd as u64);
+        self.poller
+            .update(EpollOp::EpollCtlMod, fd, &mut Some(event));
+    }
+    // Must be called before the fd is closed, epoll keeps watching a closed fd
+    // as long as the file is alive.
     pub fn deregister(&self, fd: i32) {
+        self.listeners.lock().unwrap().remove(&fd);
         self.poller.update(EpollOp::EpollCtlDel, fd, &mut None);
     }
+    pub fn is_registered(&self, fd: i32) -> bool {
+        self.listeners.lock().unwrap().contains(&fd)
+    }
+    pub fn is_running(&self) -> bool {
+        self.run
+    }
+    // Stop the loop and deregister the fds still tracked. The connections are owned
+    // by the handler, which should deregister, shutdown and drop them before.
+    pub fn quit(&mut self) {
+        self.assert_in_loop_thread();
+        let fds: Vec<i32> = self.listeners.lock().unwrap().drain().collect();
+        for fd in fds {
+            self.poller.update(EpollOp::EpollCtlDel, fd, &mut None);
+        }
+        for fd in self.timers.lock().unwrap().keys() {
+            self.poller.update(EpollOp::EpollCtlDel, *fd, &mut None);
+        }
+        self.poller
+            .update(EpollOp::EpollCtlDel, self.listener.as_raw_fd(), &mut None);
+        self.run = false;
+    }
     fn is_listen_event(&self, fd: i32) -> bool {
         self.listener.as_raw_fd() == fd
         //|| self.listeners.lock().unwrap().contains_key(&fd)
@@ -72,7 +118,9 @@ impl EventLoop {
     fn is_timer_event(&self, fd: i32) -> bool {
         self.timers.lock().unwrap().contains_key(&fd)
     }
-    pub fn add_timer(&mut self, interval: i64) {
+    // The timer fd is in the Timer token of its events
+    pub fn add_timer(&mut self, interval: i64) -> RawFd {
+        self.assert_in_loop_thread();
         let timer_fd = TimerFd::new(
             ClockId::CLOCK_MONOTONIC,
             TimerFlags::TFD_CLOEXEC | TimerFlags::TFD_NONBLOCK,
@@ -90,15 +138,40 @@ impl EventLoop {
             .unwrap();
         self.poller
             .register(timer_fd.as_raw_fd(), EVENT_READ | EVENT_LEVEL);
-        self.timers
-            .lock()
-            .unwrap()
-            .insert(timer_fd.as_raw_fd(), timer_fd);
+        let fd = timer_fd.as_raw_fd();
+        self.timers.lock().unwrap().insert(fd, timer_fd);
+        fd
+    }
+    // A timer firing once after delay, e.g. for a backoff, then removed by remove_timer
+    pub fn add_oneshot_timer(&mut self, delay: Duration) -> RawFd {
+        self.assert_in_loop_thread();
+        let timer_fd = TimerFd::new(
+            ClockId::CLOCK_MONOTONIC,
+            TimerFlags::TFD_CLOEXEC | TimerFlags::TFD_NONBLOCK,
+        )
+        .unwrap();
+        timer_fd
+            .set(Expiration::OneShot(TimeSpec::from(delay)), TimerSetTimeFlags::empty())
+            .unwrap();
+        self.poller
+            .register(timer_fd.as_raw_fd(), EVENT_READ | EVENT_LEVEL);
+        let fd = timer_fd.as_raw_fd();
+        self.timers.lock().unwrap().insert(fd, timer_fd);
+        fd
+    }
+    // The timer fd is closed by the drop
+    pub fn remove_timer(&mut self, fd: RawFd) {
+        self.assert_in_loop_thread();
+        if let Some(timer_fd) = self.timers.lock().unwrap().remove(&fd) {
+            self.poller.update(EpollOp::EpollCtlDel, fd, &mut None);
+            drop(timer_fd);
+        }
     }
     pub fn run<H>(&mut self, handler: &mut H)
     where
         H: Handler,
     {
+        self.assert_in_loop_thread();
         while self.run {
             let cnt = self.poller.poll();
             let mut ready_channels = Vec::new();
@@ -18,3 +18,6 @@ pub mod socket;
 
 #[allow(dead_code)]
 pub mod acceptor;
+
+#[allow(dead_code)]
+pub mod resolver;
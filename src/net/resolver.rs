@@ -0,0 +1,115 @@
+use log::debug;
+use nix::libc::{self, c_char, NI_MAXHOST, NI_NAMEREQD};
+use nix::sys::socket::{InetAddr, SockAddr};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+pub trait NameResolver: Send + Sync {
+    // Reverse lookup of a ip, it may block for a long time
+    fn resolve(&self, ip: &IpAddr) -> Option<String>;
+}
+
+// Reverse DNS by getnameinfo(3)
+pub struct DnsResolver;
+
+impl NameResolver for DnsResolver {
+    fn resolve(&self, ip: &IpAddr) -> Option<String> {
+        let addr = SockAddr::new_inet(InetAddr::from_std(&SocketAddr::new(*ip, 0)));
+        let (sock_addr, len) = addr.as_ffi_pair();
+        let mut host = [0 as c_char; NI_MAXHOST as usize];
+        let ret = unsafe {
+            libc::getnameinfo(
+                sock_addr,
+                len,
+                host.as_mut_ptr(),
+                host.len() as libc::socklen_t,
+                std::ptr::null_mut(),
+                0,
+                NI_NAMEREQD,
+            )
+        };
+        if ret != 0 {
+            return None;
+        }
+        let host = unsafe { CStr::from_ptr(host.as_ptr()) };
+        Some(host.to_string_lossy().to_string())
+    }
+}
+
+// Resolved host names shared by the server and sessions.
+// The ip is shown until the worker pool finish the resolution.
+#[derive(Clone)]
+pub struct NameCache {
+    resolver: Arc<dyn NameResolver>,
+    names: Arc<Mutex<HashMap<IpAddr, Option<String>>>>, // None: resolving or not found
+}
+
+impl NameCache {
+    pub fn new(resolver: Arc<dyn NameResolver>) -> Self {
+        NameCache {
+            resolver,
+            names: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    // Mark the ip as resolving, return false if it's known or resolving already
+    pub fn need_resolve(&self, ip: &IpAddr) -> bool {
+        let mut names = self.names.lock().unwrap();
+        if names.contains_key(ip) {
+            return false;
+        }
+        names.insert(*ip, None);
+        true
+    }
+    // Blocking call, must be executed in the worker pool
+    pub fn resolve(&self, ip: IpAddr) {
+        let name = self.resolver.resolve(&ip);
+        debug!("Resolve {} -> {:?}", ip, name);
+        self.names.lock().unwrap().insert(ip, name);
+    }
+    pub fn lookup(&self, ip: &IpAddr) -> String {
+        match self.names.lock().unwrap().get(ip) {
+            Some(Some(name)) => name.clone(),
+            _ => ip.to_string(),
+        }
+    }
+}
+
+impl fmt::Debug for NameCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NameCache[.. {}]", self.names.lock().unwrap().len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct StubResolver(AtomicUsize);
+
+    impl NameResolver for StubResolver {
+        fn resolve(&self, _ip: &IpAddr) -> Option<String> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Some("ftp.example.com".to_string())
+        }
+    }
+    #[test]
+    fn test_name_cache() {
+        let stub = Arc::new(StubResolver(AtomicUsize::new(0)));
+        let cache = NameCache::new(stub.clone());
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(cache.lookup(&ip), "10.0.0.1");
+
+        assert!(cache.need_resolve(&ip));
+        assert!(!cache.need_resolve(&ip));
+        assert_eq!(cache.lookup(&ip), "10.0.0.1");
+
+        cache.resolve(ip);
+        assert_eq!(cache.lookup(&ip), "ftp.example.com");
+        assert!(!cache.need_resolve(&ip));
+        assert_eq!(stub.0.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,5 +1,10 @@
+use crate::utils::retry::retry_eintr;
 use log::{debug, warn};
-use nix::sys::socket::{accept4, bind, connect, setsockopt, socket, sockopt};
+use nix::errno::Errno;
+use nix::libc::{self, c_int, c_void, socklen_t};
+use nix::sys::socket::{
+    accept4, bind, connect, getsockname, getsockopt, setsockopt, socket, sockopt,
+};
 use nix::sys::socket::{AddressFamily, InetAddr};
 use nix::sys::socket::{SockAddr, SockFlag, SockProtocol, SockType};
 use std::net::SocketAddr;
@@ -40,10 +45,63 @@ impl Socket {
     pub fn set_reuse_port(&mut self, on: bool) {
         setsockopt(self.0, sockopt::ReusePort, &on).unwrap();
     }
+    // IP_TOS for IPv4 or IPV6_TCLASS for IPv6, nix doesn't provide them
+    fn tos_option(&self) -> (c_int, c_int) {
+        match getsockname(self.0) {
+            Ok(SockAddr::Inet(InetAddr::V6(_))) => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+            _ => (libc::IPPROTO_IP, libc::IP_TOS),
+        }
+    }
+    pub fn set_tos(&mut self, tos: u8) -> nix::Result<()> {
+        let (level, name) = self.tos_option();
+        let value = tos as c_int;
+        let len = std::mem::size_of::<c_int>() as socklen_t;
+        let ptr = &value as *const c_int as *const c_void;
+        let ret = unsafe { libc::setsockopt(self.0, level, name, ptr, len) };
+        Errno::result(ret).map(drop)
+    }
+    pub fn tos(&self) -> nix::Result<u8> {
+        let (level, name) = self.tos_option();
+        let mut value: c_int = 0;
+        let mut len = std::mem::size_of::<c_int>() as socklen_t;
+        let ptr = &mut value as *mut c_int as *mut c_void;
+        let ret = unsafe { libc::getsockopt(self.0, level, name, ptr, &mut len) };
+        Errno::result(ret).map(|_| value as u8)
+    }
+    // QoS marking from the config, 0 keeps the system default
+    pub fn mark_tos(&mut self, tos: u8) {
+        if tos != 0 {
+            if let Err(e) = self.set_tos(tos) {
+                warn!("Couldn't set TOS {:#x} on {}: {}", tos, self.0, e);
+            }
+        }
+    }
+    // TCP_USER_TIMEOUT (ms), how long sent data may stay unacknowledged before the connection is dropped
+    pub fn set_user_timeout(&mut self, ms: u32) -> nix::Result<()> {
+        setsockopt(self.0, sockopt::TcpUserTimeout, &ms)
+    }
+    pub fn user_timeout(&self) -> nix::Result<u32> {
+        getsockopt(self.0, sockopt::TcpUserTimeout)
+    }
+    // From the config, 0 keeps the retransmission timeout of the system
+    pub fn mark_user_timeout(&mut self, ms: u32) {
+        if ms != 0 {
+            if let Err(e) = self.set_user_timeout(ms) {
+                warn!(
+                    "Couldn't set TCP_USER_TIMEOUT {}ms on {}: {}",
+                    ms, self.0, e
+                );
+            }
+        }
+    }
     pub fn accept(sockfd: i32) -> Self {
-        let connfd = accept4(sockfd, *NONBLOCKING_CLOEXEC).unwrap();
+        let connfd = retry_eintr(|| accept4(sockfd, *NONBLOCKING_CLOEXEC)).unwrap();
         Socket(connfd)
     }
+    // Out of fds, the error is returned at once, the event loop mustn't sleep
+    pub fn try_accept(sockfd: i32) -> nix::Result<Self> {
+        retry_eintr(|| accept4(sockfd, *NONBLOCKING_CLOEXEC)).map(Socket)
+    }
     pub fn connect(addr: &str) -> Self {
         let sockfd = socket(
             AddressFamily::Inet,
@@ -74,3 +132,39 @@ impl AsRawFd for Socket {
         self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_tos() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut sock = Socket(stream.as_raw_fd());
+        sock.set_tos(0x10).unwrap(); // IPTOS_LOWDELAY
+        assert_eq!(sock.tos().unwrap(), 0x10);
+        sock.mark_tos(0);
+        assert_eq!(sock.tos().unwrap(), 0x10);
+        sock.mark_tos(0x08); // IPTOS_THROUGHPUT
+        assert_eq!(sock.tos().unwrap(), 0x08);
+
+        let listener = TcpListener::bind("[::1]:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut sock = Socket(stream.as_raw_fd());
+        sock.set_tos(0xb8).unwrap(); // DSCP EF
+        assert_eq!(sock.tos().unwrap(), 0xb8);
+    }
+    #[test]
+    fn test_user_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut sock = Socket(stream.as_raw_fd());
+        let default = sock.user_timeout().unwrap();
+        sock.mark_user_timeout(0);
+        assert_eq!(sock.user_timeout().unwrap(), default);
+        sock.mark_user_timeout(5000);
+        assert_eq!(sock.user_timeout().unwrap(), 5000);
+    }
+}
@@ -53,13 +53,31 @@ impl<K: Hash + Eq, V> TimerList<K, V> {
             None => None,
         }
     }
-    pub fn remove_idle(&mut self) {
+    pub fn timeout(&self) -> u64 {
+        self.timeout
+    }
+    // Remove the idle nodes and hand them back, so the caller decides how to release them
+    pub fn remove_idle(&mut self) -> Vec<(K, V)> {
+        self.remove_idle_except(|_, _| false)
+    }
+    // Like remove_idle, but a timed out node is kept and refreshed if it's busy otherwise
+    pub fn remove_idle_except<F>(&mut self, mut busy: F) -> Vec<(K, V)>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let mut idle = Vec::new();
         while !self.list.is_empty() {
             match self.list.last() {
                 Some((instant, _)) => {
                     if self.timeout < instant.elapsed().as_secs() {
                         // debug!("Idle node: {}, time: {}", node, instant.elapsed().as_secs());
-                        self.list.remove_last();
+                        if let Some((k, (_, v))) = self.list.pop() {
+                            if busy(&k, &v) {
+                                self.list.put(k, (Instant::now(), v));
+                            } else {
+                                idle.push((k, v));
+                            }
+                        }
                     } else {
                         break;
                     }
@@ -67,6 +85,14 @@ impl<K: Hash + Eq, V> TimerList<K, V> {
                 None => break,
             }
         }
+        idle
+    }
+    pub fn drain(&mut self) -> Vec<(K, V)> {
+        let mut nodes = Vec::with_capacity(self.list.len());
+        while let Some((k, (_, v))) = self.list.pop() {
+            nodes.push((k, v));
+        }
+        nodes
     }
 }
 
@@ -0,0 +1,93 @@
+use crate::utils::config::LogFlush;
+use log::warn;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+// The commands of all the sessions, one line each. In the interval mode the
+// lines are flushed by the server timer, the latest ones may be lost on a crash.
+pub struct CommandLog {
+    out: Mutex<BufWriter<Box<dyn Write + Send>>>,
+    flush: LogFlush,
+}
+
+impl CommandLog {
+    pub fn new(out: Box<dyn Write + Send>, flush: LogFlush) -> Self {
+        CommandLog {
+            out: Mutex::new(BufWriter::new(out)),
+            flush,
+        }
+    }
+    pub fn open(path: &Path, flush: LogFlush) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(CommandLog::new(Box::new(file), flush))
+    }
+    pub fn log(&self, line: &str) {
+        let mut out = self.out.lock().unwrap();
+        let mut result = writeln!(out, "{}", line);
+        if self.flush == LogFlush::Line {
+            result = result.and_then(|_| out.flush());
+        }
+        if let Err(e) = result {
+            warn!("Couldn't write the command log: {}", e);
+        }
+    }
+    pub fn flush(&self) {
+        if let Err(e) = self.out.lock().unwrap().flush() {
+            warn!("Couldn't flush the command log: {}", e);
+        }
+    }
+}
+
+impl fmt::Debug for CommandLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CommandLog")
+            .field("flush", &self.flush)
+            .finish()
+    }
+}
+
+impl Drop for CommandLog {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // A writer whose content is read by the test
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_flush_policy() {
+        let buf = SharedBuf::default();
+        let log = CommandLog::new(Box::new(buf.clone()), LogFlush::Line);
+        log.log("USER tester");
+        assert_eq!(*buf.0.lock().unwrap(), b"USER tester\n");
+
+        let buf = SharedBuf::default();
+        let log = CommandLog::new(Box::new(buf.clone()), LogFlush::Interval);
+        log.log("USER tester");
+        log.log("PASS ****");
+        assert!(buf.0.lock().unwrap().is_empty());
+        log.flush();
+        assert_eq!(*buf.0.lock().unwrap(), b"USER tester\nPASS ****\n");
+        log.log("QUIT");
+        drop(log);
+        assert!(buf.0.lock().unwrap().ends_with(b"QUIT\n"));
+    }
+}
@@ -1,5 +1,6 @@
-use crate::handler::cmd::{Answer, ResultCode};
+use crate::handler::cmd::Answer;
 use crate::handler::codec::{BytesCodec, Decoder, Encoder};
+use crate::handler::reply_code::ReplyCode;
 use crate::net::connection::Connection;
 use crate::net::socket::Socket;
 use log::{debug, info, warn};
@@ -143,7 +144,7 @@ impl LocalClient {
             warn!("Not connected.");
         }
         let reply = self.send_cmd(&format!("USER {}", username)).unwrap();
-        if reply.code == ResultCode::NeedPsw {
+        if reply.code == ReplyCode::NeedPassword {
             let msg = format!("PASS {}", password);
             self.send_cmd(&msg).unwrap();
         }
@@ -246,7 +247,7 @@ impl LocalClient {
         if let Some(mut c) = self.get_data_connect() {
             let answer = self.send_cmd(&format!("RETR {}", file)).unwrap();
             println!("{}", answer);
-            if answer.code == ResultCode::DataConnOpened {
+            if answer.code == ReplyCode::DataConnAlreadyOpen {
                 let mut fd = fs::File::open(file).unwrap();
                 loop {
                     let buf = c.read_buf();
@@ -295,7 +296,7 @@ impl LocalClient {
         let start = Instant::now();
         let answer = self.send_cmd(&format!("STOR {}", file)).unwrap();
         println!("{}", answer);
-        if answer.code != ResultCode::DataConnOpened && answer.code != ResultCode::Ok {
+        if answer.code != ReplyCode::DataConnAlreadyOpen && answer.code != ReplyCode::CommandOk {
             if let Some(mut c) = self.get_data_connect() {
                 total_size += c.send_file(Some(file.as_str()), 0, None, 0).unwrap();
             }
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// Sessions logged in per user, shared by all the sessions of the server
+#[derive(Debug, Clone, Default)]
+pub struct LoginCounter {
+    counts: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl LoginCounter {
+    pub fn new() -> Self {
+        LoginCounter::default()
+    }
+    // Count a login of the user, None if it already has max sessions (0 for no limit).
+    // The login is counted until the guard is dropped.
+    pub fn acquire(&self, user: &str, max: usize) -> Option<LoginGuard> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(user.to_string()).or_insert(0);
+        if max != 0 && *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(LoginGuard {
+            counter: self.clone(),
+            user: user.to_string(),
+        })
+    }
+    pub fn count(&self, user: &str) -> usize {
+        self.counts.lock().unwrap().get(user).copied().unwrap_or(0)
+    }
+}
+
+// A logged in session, released on logout or when the session is dropped
+#[derive(Debug)]
+pub struct LoginGuard {
+    counter: LoginCounter,
+    user: String,
+}
+
+impl Drop for LoginGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counter.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.user) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.user);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_counter() {
+        let counter = LoginCounter::new();
+        let first = counter.acquire("alice", 2).unwrap();
+        let second = counter.acquire("alice", 2).unwrap();
+        assert!(counter.acquire("alice", 2).is_none());
+        assert!(counter.acquire("bob", 2).is_some());
+        assert_eq!(counter.count("alice"), 2);
+        drop(first);
+        assert_eq!(counter.count("alice"), 1);
+        let _third = counter.acquire("alice", 2).unwrap();
+        drop(second);
+        assert_eq!(counter.count("alice"), 1);
+        // No limit
+        let guards: Vec<_> = (0..10)
+            .map(|_| counter.acquire("bob", 0).unwrap())
+            .collect();
+        assert_eq!(counter.count("bob"), 10);
+        drop(guards);
+        assert_eq!(counter.count("bob"), 0);
+    }
+}
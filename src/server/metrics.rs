@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Upper bounds (ms) of the duration buckets, the last bucket is unbounded
+const DURATION_BOUNDS: [u64; 8] = [10, 100, 500, 1000, 5000, 30_000, 120_000, 600_000];
+const POW2_BUCKETS: usize = 41; // up to 1 TiB, then unbounded
+
+// Counts of the values per bucket, a value goes in the first bucket whose bound is >= it
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<u64>,
+    counts: Vec<AtomicU64>, // bounds.len() + 1, the last one for the larger values
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: Vec<u64>) -> Self {
+        let counts = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Histogram {
+            bounds,
+            counts,
+            sum: AtomicU64::new(0),
+        }
+    }
+    // Buckets 1, 2, 4, ... 2^40
+    pub fn powers_of_two() -> Self {
+        Histogram::new((0..POW2_BUCKETS).map(|i| 1u64 << i).collect())
+    }
+    pub fn record(&self, value: u64) {
+        let i = self.bounds.partition_point(|&bound| bound < value);
+        self.counts[i].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let counts = self.counts.iter().map(|c| c.load(Ordering::Relaxed));
+        let buckets = self
+            .bounds
+            .iter()
+            .map(|&bound| Some(bound))
+            .chain(std::iter::once(None))
+            .zip(counts)
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+    pub buckets: Vec<(Option<u64>, u64)>, // (upper bound, count), None is unbounded
+    pub sum: u64,
+}
+
+impl HistogramSnapshot {
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|(_, n)| n).sum()
+    }
+    // The count of the bucket bounded by bound
+    pub fn bucket(&self, bound: Option<u64>) -> u64 {
+        self.buckets
+            .iter()
+            .find(|(b, _)| *b == bound)
+            .map_or(0, |(_, n)| *n)
+    }
+}
+
+// Completed transfers of all the sessions, updated by the workers
+#[derive(Debug)]
+pub struct TransferMetrics {
+    sizes: Histogram,      // bytes
+    durations: Histogram,  // ms
+    throughput: Histogram, // bytes/s
+}
+
+impl TransferMetrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(TransferMetrics {
+            sizes: Histogram::powers_of_two(),
+            durations: Histogram::new(DURATION_BOUNDS.to_vec()),
+            throughput: Histogram::powers_of_two(),
+        })
+    }
+    pub fn record(&self, bytes: u64, elapsed: Duration) {
+        self.sizes.record(bytes);
+        self.durations.record(elapsed.as_millis() as u64);
+        let secs = elapsed.as_secs_f64().max(1e-6);
+        self.throughput.record((bytes as f64 / secs) as u64);
+    }
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            sizes: self.sizes.snapshot(),
+            durations: self.durations.snapshot(),
+            throughput: self.throughput.snapshot(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+    pub sizes: HistogramSnapshot,
+    pub durations: HistogramSnapshot,
+    pub throughput: HistogramSnapshot,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new(vec![10, 100]);
+        for value in [0, 10, 11, 100, 101, 5000] {
+            histogram.record(value);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(
+            snapshot.buckets,
+            vec![(Some(10), 2), (Some(100), 2), (None, 2)]
+        );
+        assert_eq!(snapshot.sum, 5222);
+        assert_eq!(snapshot.count(), 6);
+    }
+}
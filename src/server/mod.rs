@@ -6,3 +6,12 @@ pub mod local_client;
 
 #[allow(dead_code)]
 pub mod record_lock;
+
+#[allow(dead_code)]
+pub mod metrics;
+
+#[allow(dead_code)]
+pub mod logins;
+
+#[allow(dead_code)]
+pub mod command_log;
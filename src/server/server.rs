@@ -1,38 +1,192 @@
+use crate::handler::auth::{Authenticator, ConfigAuth};
 use crate::handler::session::Session;
+use crate::handler::transfer::TransferObserver;
 use crate::net::acceptor::Acceptor;
-use crate::net::connection::{Connection, EventSet};
+use crate::net::connection::{Connection, EventSet, TransferTracker};
 use crate::net::event_loop::{EventLoop, Handler, Token};
+use crate::net::resolver::{DnsResolver, NameCache};
 use crate::net::socket::Socket;
 use crate::net::sorted_list::TimerList;
+use crate::server::command_log::CommandLog;
+use crate::server::logins::LoginCounter;
+use crate::server::metrics::{MetricsSnapshot, TransferMetrics};
 use crate::threadpool::threadpool::ThreadPool;
-use crate::utils::config::Config;
+use crate::utils::config::{Config, LogFlush};
 use crate::utils::utils::{already_running, daemonize};
 use log::{debug, info, warn};
 use nix::sys::epoll::EpollFlags;
 use nix::unistd::read;
+use std::collections::HashMap;
 use std::net::TcpListener;
 use std::sync::{Arc, Mutex};
-use std::{os::unix::prelude::AsRawFd, path::PathBuf};
+use std::os::unix::prelude::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 const DEFAULT_TIME_OUT: u64 = 90; // time (s)
 const DEFAULT_TIMER: i64 = 2;
+// Delay before accepting again when out of fds, doubled up to the max while it lasts
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
 
 pub struct FtpServer {
     worker_pool: ThreadPool,
+    listing_pool: Option<Arc<Mutex<ThreadPool>>>, // apart from the workers waiting for it
     sessions: TimerList<i32, Arc<Mutex<Session>>>, // <cmd_fd, session_ref>
+    transfers: HashMap<i32, TransferTracker>, // read without locking a busy session
     event_loop: EventLoop,
     config: Config,
+    names: Option<NameCache>,
+    metrics: Arc<TransferMetrics>,
+    auth: Arc<dyn Authenticator>, // shared, so a reloaded user is seen by every session
+    logins: LoginCounter,
+    command_log: Option<Arc<CommandLog>>,
+    log_timer: Option<i32>, // flushes the command log in the interval mode
+    observers: Vec<Arc<dyn TransferObserver>>,
+    accept_timer: Option<(i32, i32)>, // <timer_fd, listen_fd>, set while out of fds
+    accept_backoff: Duration,
 }
 
 impl FtpServer {
     pub fn new(config: Config, event_loop: &mut EventLoop) -> Self {
         let pool = ThreadPool::new(0);
         event_loop.add_timer(5);
+        let names = if config.reverse_dns {
+            Some(NameCache::new(Arc::new(DnsResolver)))
+        } else {
+            None
+        };
+        let command_log = config.command_log.as_ref().and_then(|path| {
+            CommandLog::open(Path::new(path), config.log_flush)
+                .map_err(|e| warn!("Couldn't open the command log {}: {}", path, e))
+                .ok()
+                .map(Arc::new)
+        });
+        let log_timer = match command_log {
+            Some(_) if config.log_flush == LogFlush::Interval => {
+                Some(event_loop.add_timer(config.log_flush_interval.max(1) as i64))
+            }
+            _ => None,
+        };
+        let listing_pool = if config.stream_listing {
+            Some(Arc::new(Mutex::new(ThreadPool::new(0))))
+        } else {
+            None
+        };
         FtpServer {
             worker_pool: pool,
+            listing_pool,
             sessions: TimerList::new(DEFAULT_TIME_OUT),
+            transfers: HashMap::new(),
             event_loop: event_loop.clone(),
+            auth: Arc::new(ConfigAuth::new(config.users.clone(), None)),
             config,
+            names,
+            metrics: TransferMetrics::new(),
+            logins: LoginCounter::new(),
+            command_log,
+            log_timer,
+            observers: Vec::new(),
+            accept_timer: None,
+            accept_backoff: ACCEPT_BACKOFF,
+        }
+    }
+    pub fn set_authenticator(&mut self, auth: Arc<dyn Authenticator>) {
+        self.auth = auth;
+    }
+    // Told of the transfers of the sessions accepted from now on
+    pub fn add_transfer_observer(&mut self, observer: Arc<dyn TransferObserver>) {
+        self.observers.push(observer);
+    }
+    // Histograms of the completed transfers, for export
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+    // Tear down all the sessions and stop the event loop
+    pub fn quit(&mut self, event_loop: &mut EventLoop) {
+        for (fd, s) in self.sessions.drain() {
+            release_session(event_loop, fd, s);
+        }
+        self.transfers.clear();
+        event_loop.quit();
+    }
+    fn add_connection(&mut self, event_loop: &mut EventLoop, mut sock: Socket) {
+        sock.mark_tos(self.config.control_tos);
+        let mut conn = Connection::new(sock.clone());
+        if self.config.max_clients > self.sessions.len() || self.config.max_clients == 0 {
+            conn.register_read(event_loop);
+            info!(
+                "[{}] A new connection: {} -> {}",
+                conn.id(),
+                conn.get_peer_addr(),
+                conn.get_local_addr()
+            );
+            self.resolve_peer(&conn);
+            let mut s = Session::new(&self.config, conn, event_loop, self.names.clone());
+            s.set_metrics(self.metrics.clone());
+            s.set_authenticator(self.auth.clone());
+            s.set_login_counter(self.logins.clone());
+            if let Some(pool) = &self.listing_pool {
+                s.set_listing_pool(pool.clone());
+            }
+            if let Some(log) = &self.command_log {
+                s.set_command_log(log.clone());
+            }
+            for observer in self.observers.iter() {
+                s.add_transfer_observer(observer.clone());
+            }
+            self.transfers.insert(sock.as_raw_fd(), s.transfer_tracker());
+            self.sessions
+                .insert(sock.as_raw_fd(), Arc::new(Mutex::new(s)));
+        } else {
+            warn!(
+                "Max client number: {}, Session number: {}, shutdown conn: {}",
+                self.config.max_clients,
+                self.sessions.len(),
+                sock.as_raw_fd()
+            );
+            // A reset, the refused client gets no reply to wait for
+            conn.abort();
+        }
+    }
+    // Log out of the idle sessions. The control connection of a transfer is quiet,
+    // so a session is still alive while its transfer keeps moving bytes.
+    fn remove_idle(&mut self, event_loop: &mut EventLoop) {
+        let period = Duration::from_secs(self.sessions.timeout());
+        let transfers = &self.transfers;
+        let idle = if self.config.idle_exempt_transfers {
+            self.sessions.remove_idle_except(|fd, _| {
+                transfers.get(fd).is_some_and(|t| t.progressed_within(period))
+            })
+        } else {
+            self.sessions.remove_idle()
+        };
+        if !idle.is_empty() {
+            for (fd, s) in idle {
+                self.transfers.remove(&fd);
+                release_session(event_loop, fd, s);
+            }
+            debug!("Remove idle session, new len: {}", self.sessions.len());
+        }
+    }
+    // Out of fds, the pending connections are accepted again by a timer rather than by
+    // a sleep of the event loop. No new edge comes for them on the listener.
+    fn retry_accept_later(&mut self, event_loop: &mut EventLoop, listen_fd: i32) {
+        if self.accept_timer.is_some() {
+            return;
+        }
+        debug!("Accept on {} again in {:?}", listen_fd, self.accept_backoff);
+        let timer_fd = event_loop.add_oneshot_timer(self.accept_backoff);
+        self.accept_timer = Some((timer_fd, listen_fd));
+        self.accept_backoff = (self.accept_backoff * 2).min(MAX_ACCEPT_BACKOFF);
+    }
+    // Reverse DNS is slow, so it's resolved in the worker pool rather than the event loop
+    fn resolve_peer(&mut self, conn: &Connection) {
+        if let (Some(names), Some(ip)) = (&self.names, conn.peer_ip()) {
+            if names.need_resolve(&ip) {
+                let names = names.clone();
+                self.worker_pool.execute(move || names.resolve(ip));
+            }
         }
     }
 }
@@ -44,29 +198,15 @@ impl Handler for FtpServer {
     fn ready(&mut self, event_loop: &mut EventLoop, token: Token) {
         if let Token::Listen(listen_fd) = token {
             debug!("listen fd: {}", listen_fd);
-            let sock = Acceptor::accept(listen_fd);
-            let mut conn = Connection::new(sock.clone());
-
-            debug!("A new connection: {:?}:{}", token, sock.as_raw_fd());
-
-            if self.config.max_clients > self.sessions.len() || self.config.max_clients == 0 {
-                conn.register_read(event_loop);
-                info!(
-                    "A new connection: {} -> {}",
-                    conn.get_peer_addr(),
-                    conn.get_local_addr()
-                );
-                let s = Session::new(&self.config, conn, event_loop);
-                self.sessions
-                    .insert(sock.as_raw_fd(), Arc::new(Mutex::new(s)));
+            let (socks, error) = Acceptor::accept_pending(listen_fd);
+            for sock in socks {
+                debug!("A new connection: {:?}:{}", token, sock.as_raw_fd());
+                self.add_connection(event_loop, sock);
+            }
+            if error.is_some() {
+                self.retry_accept_later(event_loop, listen_fd);
             } else {
-                warn!(
-                    "Max client number: {}, Session number: {}, shutdown conn: {}",
-                    self.config.max_clients,
-                    self.sessions.len(),
-                    sock.as_raw_fd()
-                );
-                conn.shutdown();
+                self.accept_backoff = ACCEPT_BACKOFF;
             }
         }
     }
@@ -78,26 +218,35 @@ impl Handler for FtpServer {
                 s.lock().unwrap().set_revents(&revents);
                 debug!("Connection: {}, revents: {:?}", fd, revents);
                 if revents.is_close() || revents.is_hup() {
-                    self.sessions.remove(&fd);
-                    event_loop.deregister(fd);
+                    if let Some(s) = self.sessions.remove(&fd) {
+                        release_session(event_loop, fd, s);
+                    }
+                    self.transfers.remove(&fd);
                     debug!("Remove session: {}", fd);
                 } else {
                     // self.request_queue.push_back(s.clone());
                     let s = s.clone();
                     self.worker_pool.execute(move || {
-                        s.lock().unwrap().handle_command();
+                        let mut s = s.lock().unwrap();
+                        s.attach_to_current_thread();
+                        s.handle_command();
                     });
                 }
             } else {
                 event_loop.deregister(fd);
             }
         } else if let Token::Timer(fd) = token {
-            // Log out of some idle sessions.
-            let old_len = self.sessions.len();
-            self.sessions.remove_idle();
-            let new_len = self.sessions.len();
-            if old_len != new_len {
-                debug!("Remove idle session, new len: {}", new_len);
+            if let Some((timer_fd, listen_fd)) = self.accept_timer {
+                if timer_fd == fd {
+                    event_loop.remove_timer(fd);
+                    self.accept_timer = None;
+                    self.ready(event_loop, Token::Listen(listen_fd));
+                    return;
+                }
+            }
+            match &self.command_log {
+                Some(log) if self.log_timer == Some(fd) => log.flush(),
+                _ => self.remove_idle(event_loop),
             }
             let mut _buf = [0u8; 8];
             // Read this timer_fd otherwise repeated events are triggered.
@@ -105,23 +254,163 @@ impl Handler for FtpServer {
         }
     }
 }
-pub fn run_server(config: &PathBuf) {
+// The order matters: deregister, then shutdown, then drop. The fd is closed
+// by the last reference of the session, which may be held by a worker.
+fn release_session(event_loop: &mut EventLoop, fd: i32, session: Arc<Mutex<Session>>) {
+    if event_loop.is_registered(fd) {
+        event_loop.deregister(fd);
+    }
+    // A busy worker holds the lock, the session is shutdown by its drop then
+    if let Ok(mut s) = session.try_lock() {
+        s.shutdown();
+    }
+    drop(session);
+}
+
+pub fn run_server(path: &PathBuf) {
     if already_running() {
         warn!("Already running...");
         return;
     }
     daemonize();
 
-    let config = Config::new(&config);
+    let config = Config::new(path);
     debug!("config: {:#?}", config);
     let addr = format!("{}:{}", config.server_addr, config.server_port);
     info!("Start server listen, addr: {}", addr);
 
     let listener = TcpListener::bind(&addr).unwrap();
+    // Accept until EAGAIN in the edge-triggered event loop
+    listener.set_nonblocking(true).unwrap();
     let listener = Socket(listener.as_raw_fd());
     debug!("listen socket: {:?}", listener);
 
     let mut event_loop = EventLoop::new(listener);
+    let auth = ConfigAuth::new(config.users.clone(), Some(path.clone()));
     let mut ftpserver = FtpServer::new(config, &mut event_loop);
+    ftpserver.set_authenticator(Arc::new(auth));
     event_loop.run(&mut ftpserver);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::connection::TransferState;
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::os::unix::net::UnixStream;
+    use std::os::unix::prelude::IntoRawFd;
+    use std::thread::sleep;
+
+    #[test]
+    fn test_quit_teardown() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = listener.try_clone().unwrap();
+        let mut event_loop = EventLoop::new(Socket(listener.into_raw_fd()));
+        let mut server = FtpServer::new(Config::default(), &mut event_loop);
+
+        let mut clients = Vec::new();
+        let mut fds = Vec::new();
+        for _ in 0..4 {
+            clients.push(TcpStream::connect(addr).unwrap());
+            let (stream, _) = acceptor.accept().unwrap();
+            let mut conn = Connection::new(Socket(stream.into_raw_fd()));
+            conn.register_read(&mut event_loop);
+            let fd = conn.get_fd().as_raw_fd();
+            assert!(event_loop.is_registered(fd));
+            let s = Session::new(&server.config, conn, &mut event_loop, None);
+            server.sessions.insert(fd, Arc::new(Mutex::new(s)));
+            fds.push(fd);
+        }
+
+        server.quit(&mut event_loop);
+        assert!(!event_loop.is_running());
+        assert_eq!(server.sessions.len(), 0);
+        for fd in fds {
+            assert!(!event_loop.is_registered(fd));
+        }
+        for mut client in clients {
+            let mut buf = [0u8; 8];
+            assert_eq!(client.read(&mut buf).unwrap(), 0);
+        }
+    }
+    #[test]
+    fn test_accept_retry_timer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listen_fd = listener.as_raw_fd();
+        let mut event_loop = EventLoop::new(Socket(listener.into_raw_fd()));
+        let mut server = FtpServer::new(Config::default(), &mut event_loop);
+        let _client = TcpStream::connect(addr).unwrap();
+
+        // Out of fds, the pending connection waits for one timer, the backoff grows
+        server.retry_accept_later(&mut event_loop, listen_fd);
+        server.retry_accept_later(&mut event_loop, listen_fd);
+        assert_eq!(server.accept_backoff, ACCEPT_BACKOFF * 2);
+        let (timer, _) = server.accept_timer.unwrap();
+        server.notify(&mut event_loop, Token::Timer(timer), EpollFlags::EPOLLIN);
+        assert!(server.accept_timer.is_none());
+        assert_eq!(server.sessions.len(), 1);
+        assert_eq!(server.accept_backoff, ACCEPT_BACKOFF);
+        server.quit(&mut event_loop);
+    }
+    #[test]
+    fn test_command_log_timer() {
+        let path = std::env::temp_dir().join(format!("miniftp_cmdlog_{}", std::process::id()));
+        let mut config = Config::default();
+        config.command_log = Some(path.to_str().unwrap().to_string());
+        config.log_flush = LogFlush::Interval;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut event_loop = EventLoop::new(Socket(listener.into_raw_fd()));
+        let mut server = FtpServer::new(config, &mut event_loop);
+        let log = server.command_log.clone().unwrap();
+        log.log("127.0.0.1:2000 - NOOP");
+        assert!(std::fs::read(&path).unwrap().is_empty());
+        let timer = server.log_timer.unwrap();
+        server.notify(&mut event_loop, Token::Timer(timer), EpollFlags::EPOLLIN);
+        assert_eq!(std::fs::read(&path).unwrap(), b"127.0.0.1:2000 - NOOP\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_idle_transfer_kept() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = listener.try_clone().unwrap();
+        let mut event_loop = EventLoop::new(Socket(listener.into_raw_fd()));
+        let mut server = FtpServer::new(Config::default(), &mut event_loop);
+        server.sessions = TimerList::new(1);
+
+        let mut clients = Vec::new();
+        let mut fds = Vec::new();
+        for _ in 0..2 {
+            clients.push(TcpStream::connect(addr).unwrap());
+            let (stream, _) = acceptor.accept().unwrap();
+            let conn = Connection::new(Socket(stream.into_raw_fd()));
+            let fd = conn.get_fd().as_raw_fd();
+            let s = Session::new(&server.config, conn, &mut event_loop, None);
+            server.transfers.insert(fd, s.transfer_tracker());
+            server.sessions.insert(fd, Arc::new(Mutex::new(s)));
+            fds.push(fd);
+        }
+        // The first session downloads, its control connection stays quiet
+        let (local, _peer) = UnixStream::pair().unwrap();
+        let mut data = Connection::new(Socket(local.into_raw_fd()));
+        data.track_transfer(server.transfers[&fds[0]].clone());
+        data.set_transfer_state(TransferState::Sending);
+        for _ in 0..25 {
+            data.add_transferred(4096);
+            sleep(Duration::from_millis(100));
+            server.remove_idle(&mut event_loop);
+        }
+        assert!(server.sessions.contains(&fds[0]));
+        assert!(!server.sessions.contains(&fds[1]));
+
+        // A stalled transfer doesn't keep the session
+        sleep(Duration::from_millis(2100));
+        server.remove_idle(&mut event_loop);
+        assert_eq!(server.sessions.len(), 0);
+        assert!(server.transfers.is_empty());
+    }
+}
@@ -3,6 +3,7 @@ use super::queue::BlockingQueueRef;
 use log::debug;
 use num_cpus;
 use std::collections::HashMap;
+use std::fmt;
 use std::sync::Arc;
 use std::thread;
 
@@ -64,6 +65,15 @@ impl ThreadPool {
     }
 }
 
+impl fmt::Debug for ThreadPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ThreadPool")
+            .field("workers", &self.workers.len())
+            .field("queued", &self.sender.len())
+            .finish()
+    }
+}
+
 impl Drop for ThreadPool {
     fn drop(&mut self) {
         debug!("Sending terminate message to all workers.");
@@ -12,19 +12,113 @@ pub const DEFAULT_CONF_FILE: &'static str = "config.yaml";
 pub type User = (String, String);
 pub type Users = HashMap<String, String>;
 
+// When the command log reaches the disk
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFlush {
+    Line,     // each line, durable
+    Interval, // every log_flush_interval, fewer syscalls
+}
+
+// What a TYPE A RETR of a file with binary content does
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BinaryGuard {
+    Off,
+    Warn,   // log it and send the file
+    Refuse, // 550, the client should use TYPE I
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
 pub struct Config {
     pub server_addr: String,
     pub server_port: u16,
     pub pasv_enable: bool,
     pub pasv_port: Vec<u16>,
     pub max_clients: usize,
+    pub max_logins_per_user: usize, // 0 for no limit
     pub max_speed: i64,
     pub ssl_enable: bool,
     pub rsa_cert_file: Option<String>,
     pub rsa_private_key_file: Option<String>,
     pub admin: Option<String>,
     pub users: Users,
+    pub reverse_dns: bool,
+    pub reject_privileged_data_ports: bool,
+    pub ignore_port_ip: bool,
+    pub fsync_on_close: bool,
+    pub fsync_dir: bool,
+    pub delete_partial_upload: bool,
+    pub max_arg_len: usize,
+    pub max_failed_commands: usize, // error replies in a row before the client is dropped, 0 for no limit
+    pub max_mutations_per_sec: u32, // DELE, RMD, RNTO and MKD of a session, 0 for no limit
+    pub max_commands_per_read: usize,
+    pub max_pending_commands: usize, // stop reading the control connection beyond it
+    pub idle_exempt_transfers: bool, // a transfer moving bytes keeps the session alive
+    pub syst_reply: String,
+    pub transfer_summary: bool, // the size and rate of a transfer in its 226 reply
+    pub force_userspace_transfer: bool, // RETR reads and writes the file instead of sendfile
+    pub stream_listing: bool, // generate LIST and NLST through a pipe spliced to the data connection
+    pub ascii_binary_guard: BinaryGuard, // NUL bytes in the first block of a TYPE A RETR
+    pub mdtm_set: bool,       // MDTM time path sets the time like MFMT, MDTM path always queries it
+    pub jail: bool,           // CWD and LIST stay in the server root
+    pub follow_symlinks: bool, // a symlink is followed when its canonical target passes the jail
+    pub data_connect_timeout: u64, // seconds
+    pub disabled_commands: Vec<String>, // answered with 502
+    pub command_log: Option<String>, // file of the commands of all the sessions
+    pub log_flush: LogFlush,
+    pub log_flush_interval: u64, // seconds
+    pub control_tos: u8,
+    pub data_tos: u8,
+    pub data_user_timeout: u32, // ms, TCP_USER_TIMEOUT of the data connections
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_addr: String::from_str("0.0.0.0").unwrap(),
+            server_port: DEFAULT_PORT,
+            pasv_enable: true,
+            pasv_port: vec![2222, 2222],
+            max_clients: 0,
+            max_logins_per_user: 0,
+            max_speed: -1,
+            ssl_enable: false,
+            rsa_cert_file: None,
+            rsa_private_key_file: None,
+            admin: Some(String::new()),
+            users: HashMap::from([("anonymous".to_string(), "".to_string())]),
+            reverse_dns: false,
+            reject_privileged_data_ports: true,
+            ignore_port_ip: false,
+            fsync_on_close: false,
+            fsync_dir: false,
+            delete_partial_upload: false,
+            max_arg_len: 1024,
+            max_failed_commands: 0,
+            max_mutations_per_sec: 0,
+            max_commands_per_read: 16,
+            max_pending_commands: 128,
+            idle_exempt_transfers: true,
+            syst_reply: String::from("UNIX Type: L8"),
+            transfer_summary: false,
+            force_userspace_transfer: false,
+            stream_listing: false,
+            ascii_binary_guard: BinaryGuard::Off,
+            mdtm_set: false,
+            jail: false,
+            follow_symlinks: true,
+            data_connect_timeout: 30,
+            disabled_commands: Vec::new(),
+            command_log: None,
+            log_flush: LogFlush::Line,
+            log_flush_interval: 5,
+            control_tos: 0,
+            data_tos: 0,
+            data_user_timeout: 0,
+        }
+    }
 }
 
 pub fn get_content(path: &Path) -> Option<String> {
@@ -48,19 +142,7 @@ impl Config {
                 "No config file found so creating new one in {}",
                 DEFAULT_CONF_FILE
             );
-            let config = Config {
-                server_addr: String::from_str("0.0.0.0").unwrap(),
-                server_port: 8089u16,
-                pasv_enable: true,
-                pasv_port: vec![2222, 2222],
-                max_clients: 0,
-                max_speed: -1,
-                ssl_enable: false,
-                rsa_cert_file: None,
-                rsa_private_key_file: None,
-                admin: Some(String::new()),
-                users: HashMap::from([("anonymous".to_string(), "".to_string())]),
-            };
+            let config = Config::default();
 
             let content = serde_yaml::to_string(&config).expect("serialization failed");
             let mut file = File::create(DEFAULT_CONF_FILE).expect("couldn't create file...");
@@ -6,3 +6,6 @@ pub mod macro_utils;
 
 #[allow(dead_code)]
 pub mod utils;
+
+#[allow(dead_code)]
+pub mod retry;
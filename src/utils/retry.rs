@@ -0,0 +1,76 @@
+use log::debug;
+use nix::errno::Errno;
+use std::thread;
+use std::time::Duration;
+
+const FIRST_BACKOFF: Duration = Duration::from_millis(1);
+
+// Run a syscall again while a signal interrupts it
+pub fn retry_eintr<T>(mut f: impl FnMut() -> nix::Result<T>) -> nix::Result<T> {
+    loop {
+        match f() {
+            Err(Errno::EINTR) => continue,
+            result => return result,
+        }
+    }
+}
+
+// Like retry_eintr, and wait a little for other fds to be released when out of them.
+// The delay doubles from 1 ms for at most attempts retries, then the error is returned.
+// It sleeps, so it's not for the event loop thread.
+pub fn retry_backoff<T>(attempts: u32, mut f: impl FnMut() -> nix::Result<T>) -> nix::Result<T> {
+    let mut delay = FIRST_BACKOFF;
+    for _ in 0..attempts {
+        match retry_eintr(&mut f) {
+            Err(e @ Errno::EMFILE) | Err(e @ Errno::ENFILE) => {
+                debug!("{}, retry in {:?}", e, delay);
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    retry_eintr(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry() {
+        let mut calls = 0;
+        let result = retry_eintr(|| {
+            calls += 1;
+            if calls <= 2 {
+                Err(Errno::EINTR)
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result, Ok(3));
+        assert_eq!(
+            retry_eintr(|| Err::<(), _>(Errno::EAGAIN)),
+            Err(Errno::EAGAIN)
+        );
+
+        let mut calls = 0;
+        let result = retry_backoff(3, || {
+            calls += 1;
+            match calls {
+                1 => Err(Errno::EMFILE),
+                2 => Err(Errno::EINTR),
+                _ => Ok(calls),
+            }
+        });
+        assert_eq!(result, Ok(3));
+        // Bounded, the last error is returned
+        let mut calls = 0;
+        let result = retry_backoff(2, || {
+            calls += 1;
+            Err::<(), _>(Errno::EMFILE)
+        });
+        assert_eq!(result, Err(Errno::EMFILE));
+        assert_eq!(calls, 3);
+    }
+}